@@ -0,0 +1,257 @@
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use easy_ml::differentiation::Record;
+use easy_ml::linear_algebra;
+
+use std::fmt;
+
+use crate::{Dataset, Image, NeuralNetwork, MBTI};
+
+/// The steps `robustness` takes with projected gradient descent add up to this
+/// multiple of epsilon, so each of the `pgd_steps` steps is 2.5·ε / `pgd_steps`.
+/// Taking more than ε in total lets the attack reach the edge of the epsilon ball
+/// and still move along it once there.
+const PGD_STEP_FRACTION: f64 = 2.5;
+
+/// Accuracy on a dataset when every image is perturbed by up to epsilon
+#[derive(Clone, Debug, Serialize)]
+pub struct AttackAccuracy {
+    epsilon: f64,
+    fgsm_accuracy: f64,
+    /// None if no projected gradient descent steps were requested
+    pgd_accuracy: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RobustnessReport {
+    clean_accuracy: f64,
+    attacks: Vec<AttackAccuracy>,
+}
+
+/// The reasons an attack, or adversarial training with it, can't be made
+#[derive(Debug)]
+pub(crate) enum AttackError {
+    /// Epsilon must be a finite number of at least 0
+    InvalidEpsilon(f64),
+    /// The step size must be a finite number of at least 0
+    InvalidStepSize(f64),
+    /// The ratio of adversarial copies to clean images must be from 0 to 1
+    RatioOutOfRange(f64),
+}
+
+impl fmt::Display for AttackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttackError::InvalidEpsilon(epsilon) => {
+                write!(f, "Epsilon must be 0 or more, but was {}", epsilon)
+            }
+            AttackError::InvalidStepSize(step_size) => {
+                write!(f, "Step size must be 0 or more, but was {}", step_size)
+            }
+            AttackError::RatioOutOfRange(ratio) => write!(
+                f,
+                "Ratio of adversarial images must be between 0 and 1, but was {}",
+                ratio
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AttackError {}
+
+impl From<AttackError> for JsValue {
+    fn from(error: AttackError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+/// Checks an attack's epsilon or step size is a distance pixels can move by
+pub(crate) fn check_distance(
+    distance: f64,
+    error: fn(f64) -> AttackError,
+) -> Result<(), AttackError> {
+    if distance.is_finite() && distance >= 0.0 {
+        Ok(())
+    } else {
+        Err(error(distance))
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Creates an adversarial copy of an image with the Fast Gradient Sign Method,
+    /// moving every pixel by epsilon in whichever direction increases the training
+    /// loss for the true label, while keeping pixels within the 0-1 range.
+    pub fn fgsm(&self, image: &Image, label: MBTI, epsilon: f64) -> Result<Image, JsValue> {
+        check_distance(epsilon, AttackError::InvalidEpsilon)?;
+        Ok(Image {
+            data: self.fgsm_pixels(&image.data, usize::from(label), epsilon),
+        })
+    }
+
+    /// Creates an adversarial copy of an image with projected gradient descent,
+    /// taking `steps` signed gradient steps of `step_size` to increase the training
+    /// loss for the true label, and projecting back so no pixel moves more than
+    /// epsilon from the original image or leaves the 0-1 range.
+    pub fn pgd(
+        &self,
+        image: &Image,
+        label: MBTI,
+        epsilon: f64,
+        step_size: f64,
+        steps: usize,
+    ) -> Result<Image, JsValue> {
+        check_distance(epsilon, AttackError::InvalidEpsilon)?;
+        check_distance(step_size, AttackError::InvalidStepSize)?;
+        Ok(Image {
+            data: self.pgd_pixels(&image.data, usize::from(label), epsilon, step_size, steps),
+        })
+    }
+
+    /// Computes the accuracy on a dataset when every image is attacked with FGSM
+    /// and with projected gradient descent at each of the epsilons. Projected
+    /// gradient descent takes `pgd_steps` steps, and is skipped if this is 0.
+    pub fn robustness(
+        &self,
+        dataset: &Dataset,
+        epsilons: Vec<f64>,
+        pgd_steps: usize,
+    ) -> Result<JsValue, JsValue> {
+        for &epsilon in epsilons.iter() {
+            check_distance(epsilon, AttackError::InvalidEpsilon)?;
+        }
+        let report = RobustnessReport {
+            clean_accuracy: self.accuracy(dataset),
+            attacks: epsilons
+                .iter()
+                .map(|&epsilon| AttackAccuracy {
+                    epsilon,
+                    fgsm_accuracy: self.accuracy_under_attack(dataset, |image, class| {
+                        self.fgsm_pixels(image, class, epsilon)
+                    }),
+                    pgd_accuracy: if pgd_steps == 0 {
+                        None
+                    } else {
+                        let step_size = PGD_STEP_FRACTION * epsilon / pgd_steps as f64;
+                        Some(self.accuracy_under_attack(dataset, |image, class| {
+                            self.pgd_pixels(image, class, epsilon, step_size, pgd_steps)
+                        }))
+                    },
+                })
+                .collect(),
+        };
+        Ok(serde_wasm_bindgen::to_value(&report).expect("Failed to serialise robustness report"))
+    }
+}
+
+impl NeuralNetwork {
+    /// Differentiates the training loss of an image with respect to its pixels
+    pub(crate) fn loss_gradient(&self, pixels: &[f64], class: usize) -> Vec<f64> {
+        self.input_gradient(pixels, |output| {
+            let classification = linear_algebra::softmax(output.row_major_iter());
+            Record::constant(1.0) - classification[class]
+        })
+    }
+
+    fn fgsm_pixels(&self, pixels: &[f64], class: usize, epsilon: f64) -> Vec<f64> {
+        self.loss_gradient(pixels, class)
+            .iter()
+            .zip(pixels.iter())
+            .map(|(gradient, pixel)| (pixel + epsilon * sign(*gradient)).clamp(0.0, 1.0))
+            .collect()
+    }
+
+    fn pgd_pixels(
+        &self,
+        pixels: &[f64],
+        class: usize,
+        epsilon: f64,
+        step_size: f64,
+        steps: usize,
+    ) -> Vec<f64> {
+        let mut adversarial = pixels.to_vec();
+        for _ in 0..steps {
+            let gradient = self.loss_gradient(&adversarial, class);
+            for ((pixel, original), gradient) in adversarial
+                .iter_mut()
+                .zip(pixels.iter())
+                .zip(gradient.iter())
+            {
+                let stepped = *pixel + step_size * sign(*gradient);
+                *pixel = stepped
+                    .clamp(original - epsilon, original + epsilon)
+                    .clamp(0.0, 1.0);
+            }
+        }
+        adversarial
+    }
+
+    fn accuracy_under_attack<F>(&self, dataset: &Dataset, attack: F) -> f64
+    where
+        F: Fn(&[f64], usize) -> Vec<f64>,
+    {
+        let mut correct = 0;
+        for (image, &label) in dataset.images.iter().zip(dataset.labels.iter()) {
+            let adversarial = Image {
+                data: attack(&image.data, usize::from(label)),
+            };
+            if self.classify(&adversarial) == label {
+                correct += 1;
+            }
+        }
+        (correct as f64) / (dataset.images.len() as f64)
+    }
+}
+
+/// The sign of a gradient, treating a gradient of exactly 0 as no direction
+pub(crate) fn sign(x: f64) -> f64 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+
+    #[test]
+    fn rejects_distances_pixels_cannot_move_by() {
+        for distance in [-0.1, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                check_distance(distance, AttackError::InvalidEpsilon),
+                Err(AttackError::InvalidEpsilon(_))
+            ));
+        }
+        check_distance(0.0, AttackError::InvalidEpsilon).unwrap();
+        check_distance(0.3, AttackError::InvalidStepSize).unwrap();
+    }
+
+    #[test]
+    fn pgd_stays_within_epsilon() {
+        let network = random_network(0);
+        let pixels: Vec<f64> = (0..crate::WIDTH * crate::HEIGHT)
+            .map(|i| (i % 7) as f64 / 6.0)
+            .collect();
+        let epsilon = 0.1;
+        let steps = 4;
+        let adversarial = network.pgd_pixels(
+            &pixels,
+            3,
+            epsilon,
+            PGD_STEP_FRACTION * epsilon / steps as f64,
+            steps,
+        );
+        for (original, attacked) in pixels.iter().zip(adversarial.iter()) {
+            assert!((attacked - original).abs() <= epsilon + 1e-12);
+            assert!((0.0..=1.0).contains(attacked));
+        }
+        assert_ne!(adversarial, pixels);
+    }
+}
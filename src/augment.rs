@@ -0,0 +1,306 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{Image, SeededRandomGenerator, HEIGHT, WIDTH};
+
+/// A configurable set of random transformations applied to training images so
+/// the network sees slightly different heatmaps each epoch. Each transformation
+/// is applied independently with its own probability, and every transformation
+/// starts disabled with a probability of 0.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct Augmentation {
+    seed: u64,
+    noise: Transform<f64>,
+    translation: Transform<usize>,
+    scaling: Transform<(f64, f64)>,
+    gamma: Transform<(f64, f64)>,
+    dropout: Transform<f64>,
+    horizontal_flip: f64,
+    vertical_flip: f64,
+}
+
+/// The probability of applying a transformation and how strong it should be
+#[derive(Clone, Copy, Debug)]
+struct Transform<T> {
+    probability: f64,
+    strength: T,
+}
+
+impl<T> Transform<T> {
+    fn disabled(strength: T) -> Transform<T> {
+        Transform {
+            probability: 0.0,
+            strength,
+        }
+    }
+}
+
+fn check_probability(probability: f64) {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "Probability must be between 0 and 1"
+    );
+}
+
+#[wasm_bindgen]
+impl Augmentation {
+    /// Creates an augmentation which applies no transformations until they are
+    /// configured. Augmenting the same image with the same seed and stream always
+    /// gives the same result.
+    pub fn new(seed: u32) -> Augmentation {
+        Augmentation {
+            seed: seed as u64,
+            noise: Transform::disabled(0.0),
+            translation: Transform::disabled(0),
+            scaling: Transform::disabled((1.0, 1.0)),
+            gamma: Transform::disabled((1.0, 1.0)),
+            dropout: Transform::disabled(0.0),
+            horizontal_flip: 0.0,
+            vertical_flip: 0.0,
+        }
+    }
+
+    /// Adds Gaussian noise with the standard deviation to every pixel.
+    pub fn set_noise(&mut self, probability: f64, standard_deviation: f64) {
+        check_probability(probability);
+        self.noise = Transform {
+            probability,
+            strength: standard_deviation,
+        };
+    }
+
+    /// Moves the image by up to `max_shift` pixels horizontally and vertically,
+    /// filling the uncovered edge with zeros.
+    pub fn set_translation(&mut self, probability: f64, max_shift: usize) {
+        check_probability(probability);
+        self.translation = Transform {
+            probability,
+            strength: max_shift,
+        };
+    }
+
+    /// Multiplies every pixel by a factor picked uniformly between min and max.
+    pub fn set_intensity_scaling(&mut self, probability: f64, min: f64, max: f64) {
+        check_probability(probability);
+        assert!(
+            min <= max,
+            "Minimum scale must not be larger than the maximum"
+        );
+        self.scaling = Transform {
+            probability,
+            strength: (min, max),
+        };
+    }
+
+    /// Raises every pixel to a power picked uniformly between min and max, which
+    /// brightens the image for powers below 1 and darkens it for powers above 1.
+    pub fn set_gamma(&mut self, probability: f64, min: f64, max: f64) {
+        check_probability(probability);
+        assert!(
+            0.0 < min && min <= max,
+            "Gamma must be positive and the minimum must not be larger than the maximum"
+        );
+        self.gamma = Transform {
+            probability,
+            strength: (min, max),
+        };
+    }
+
+    /// Sets each pixel to 0 with the given rate.
+    pub fn set_dropout(&mut self, probability: f64, rate: f64) {
+        check_probability(probability);
+        check_probability(rate);
+        self.dropout = Transform {
+            probability,
+            strength: rate,
+        };
+    }
+
+    /// Mirrors the image left to right and top to bottom with the given probabilities.
+    pub fn set_flips(&mut self, horizontal_probability: f64, vertical_probability: f64) {
+        check_probability(horizontal_probability);
+        check_probability(vertical_probability);
+        self.horizontal_flip = horizontal_probability;
+        self.vertical_flip = vertical_probability;
+    }
+
+    /// Applies the augmentation to an image, for previewing what the network will
+    /// be trained on. Each sample number gives a different reproducible result.
+    pub fn apply(&self, image: &Image, sample: u32) -> Image {
+        self.augment(image, &[sample as u64])
+    }
+}
+
+impl Augmentation {
+    /// Randomly transforms a copy of the image, using random numbers reproducible
+    /// from the seed and the stream, which should be unique to each time an image
+    /// is augmented.
+    pub(crate) fn augment(&self, image: &Image, stream: &[u64]) -> Image {
+        let mut random = SeededRandomGenerator::from_stream(self.seed, stream);
+        let mut pixels = image.data.clone();
+        if random.uniform() < self.horizontal_flip {
+            for row in pixels.chunks_mut(WIDTH) {
+                row.reverse();
+            }
+        }
+        if random.uniform() < self.vertical_flip {
+            let flipped: Vec<f64> = pixels.chunks(WIDTH).rev().flatten().cloned().collect();
+            pixels = flipped;
+        }
+        if random.uniform() < self.translation.probability {
+            let max = self.translation.strength as f64;
+            // pick whole shifts uniformly from -max to max inclusive
+            let mut shift =
+                || ((2.0 * max + 1.0) * random.uniform()).floor() as isize - max as isize;
+            let (dx, dy) = (shift(), shift());
+            pixels = translate(&pixels, dx, dy);
+        }
+        if random.uniform() < self.scaling.probability {
+            let (min, max) = self.scaling.strength;
+            let factor = min + (max - min) * random.uniform();
+            pixels.iter_mut().for_each(|pixel| *pixel *= factor);
+        }
+        if random.uniform() < self.gamma.probability {
+            let (min, max) = self.gamma.strength;
+            let gamma = min + (max - min) * random.uniform();
+            pixels
+                .iter_mut()
+                .for_each(|pixel| *pixel = pixel.max(0.0).powf(gamma));
+        }
+        if random.uniform() < self.noise.probability {
+            for pixel in pixels.iter_mut() {
+                *pixel += self.noise.strength * random.normal();
+            }
+        }
+        if random.uniform() < self.dropout.probability {
+            for pixel in pixels.iter_mut() {
+                if random.uniform() < self.dropout.strength {
+                    *pixel = 0.0;
+                }
+            }
+        }
+        Image {
+            data: pixels.iter().map(|pixel| pixel.clamp(0.0, 1.0)).collect(),
+        }
+    }
+}
+
+/// Moves every pixel dx to the right and dy down, filling with zeros
+fn translate(pixels: &[f64], dx: isize, dy: isize) -> Vec<f64> {
+    let mut translated = vec![0.0; WIDTH * HEIGHT];
+    for y in 0..HEIGHT as isize {
+        for x in 0..WIDTH as isize {
+            let (from_x, from_y) = (x - dx, y - dy);
+            if (0..WIDTH as isize).contains(&from_x) && (0..HEIGHT as isize).contains(&from_y) {
+                translated[(y as usize) * WIDTH + x as usize] =
+                    pixels[(from_y as usize) * WIDTH + from_x as usize];
+            }
+        }
+    }
+    translated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image whose pixels increase from 0 at the top left in row major order
+    fn gradient() -> Image {
+        Image {
+            data: (0..WIDTH * HEIGHT)
+                .map(|i| i as f64 / (WIDTH * HEIGHT) as f64)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn same_seed_and_stream_give_the_same_image() {
+        let mut augmentation = Augmentation::new(7);
+        augmentation.set_noise(1.0, 0.1);
+        augmentation.set_translation(0.5, 2);
+        augmentation.set_dropout(0.5, 0.2);
+        let image = gradient();
+        let first = augmentation.augment(&image, &[3, 11]);
+        assert_eq!(first.data, augmentation.augment(&image, &[3, 11]).data);
+        assert_ne!(first.data, augmentation.augment(&image, &[3, 12]).data);
+        assert_ne!(
+            first.data,
+            Augmentation {
+                seed: 8,
+                ..augmentation
+            }
+            .augment(&image, &[3, 11])
+            .data
+        );
+    }
+
+    #[test]
+    fn translation_fills_the_uncovered_edge_with_zeros() {
+        let image = gradient();
+        let translated = translate(&image.data, 2, -1);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let expected = if x < 2 || y == HEIGHT - 1 {
+                    0.0
+                } else {
+                    image.data[(y + 1) * WIDTH + x - 2]
+                };
+                assert_eq!(translated[y * WIDTH + x], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn flips_mirror_the_image() {
+        let image = gradient();
+        let mut augmentation = Augmentation::new(0);
+        augmentation.set_flips(1.0, 0.0);
+        let flipped = augmentation.augment(&image, &[0]);
+        augmentation.set_flips(0.0, 1.0);
+        let upside_down = augmentation.augment(&image, &[0]);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let pixel = image.data[y * WIDTH + x];
+                assert_eq!(flipped.data[y * WIDTH + (WIDTH - 1 - x)], pixel);
+                assert_eq!(upside_down.data[(HEIGHT - 1 - y) * WIDTH + x], pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn dropout_zeroes_pixels_at_the_rate() {
+        let image = Image {
+            data: vec![1.0; WIDTH * HEIGHT],
+        };
+        let mut augmentation = Augmentation::new(1);
+        augmentation.set_dropout(1.0, 0.3);
+        let samples = 200;
+        let dropped: usize = (0..samples)
+            .map(|sample| {
+                augmentation
+                    .augment(&image, &[sample])
+                    .data
+                    .iter()
+                    .filter(|&&pixel| pixel == 0.0)
+                    .count()
+            })
+            .sum();
+        let rate = dropped as f64 / (samples as usize * WIDTH * HEIGHT) as f64;
+        assert!((rate - 0.3).abs() < 0.01, "Dropout rate was {}", rate);
+    }
+
+    #[test]
+    fn transformations_with_probability_0_leave_the_image_unchanged() {
+        let image = gradient();
+        let mut augmentation = Augmentation::new(2);
+        augmentation.set_noise(0.0, 0.5);
+        augmentation.set_translation(0.0, 3);
+        augmentation.set_intensity_scaling(0.0, 0.5, 2.0);
+        augmentation.set_gamma(0.0, 0.5, 2.0);
+        augmentation.set_dropout(0.0, 0.5);
+        augmentation.set_flips(0.0, 0.0);
+        for sample in 0..10 {
+            assert_eq!(augmentation.augment(&image, &[sample]).data, image.data);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use easy_ml::matrices::Matrix;
+
+use std::convert::TryInto;
+
+use crate::format::{check_layer_count, check_layer_shape, ModelFileError};
+use crate::NeuralNetwork;
+
+/// The first four bytes of every binary model
+const MAGIC: &[u8; 4] = b"MBTN";
+const BINARY_VERSION: u32 = 1;
+
+/// How many bytes each weight is stored in by `NeuralNetwork::to_bytes`
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeightPrecision {
+    /// 32 bit floats, half the size but rounds every weight
+    Single = 4,
+    /// 64 bit floats, which reproduce the weights exactly
+    Double = 8,
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Serialises the neural network to a compact binary format. All values are
+    /// little endian, laid out as:
+    ///
+    /// - the magic bytes `MBTN`
+    /// - the format version as a u32
+    /// - the bytes per weight as a u32, 4 or 8
+    /// - the epochs trained as an i32
+    /// - the softmax temperature as an f64
+    /// - the number of weight matrices as a u32
+    /// - the rows and columns of each weight matrix as u32s
+    /// - the weights of every matrix in row major order
+    ///
+    /// Only what is needed to classify and keep training is stored, so the
+    /// training history and privacy budget are left out. Use `to_json` to keep
+    /// them.
+    pub fn to_bytes(&self, precision: WeightPrecision) -> Vec<u8> {
+        let weights: usize = self
+            .weights
+            .iter()
+            .map(Matrix::size)
+            .map(|(r, c)| r * c)
+            .sum();
+        let mut bytes =
+            Vec::with_capacity(28 + 8 * self.weights.len() + precision as usize * weights);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(precision as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.epochs.to_le_bytes());
+        bytes.extend_from_slice(&self.temperature.to_le_bytes());
+        bytes.extend_from_slice(&(self.weights.len() as u32).to_le_bytes());
+        for matrix in &self.weights {
+            bytes.extend_from_slice(&(matrix.rows() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(matrix.columns() as u32).to_le_bytes());
+        }
+        for matrix in &self.weights {
+            for weight in matrix.row_major_iter() {
+                match precision {
+                    WeightPrecision::Single => {
+                        bytes.extend_from_slice(&(weight as f32).to_le_bytes())
+                    }
+                    WeightPrecision::Double => bytes.extend_from_slice(&weight.to_le_bytes()),
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Deserialises a neural network from the binary format written by `to_bytes`,
+    /// rejecting data for a different architecture or of the wrong length.
+    pub fn from_bytes(bytes: &[u8]) -> Result<NeuralNetwork, JsValue> {
+        NeuralNetwork::read_bytes(bytes).map_err(JsValue::from)
+    }
+}
+
+impl NeuralNetwork {
+    fn read_bytes(bytes: &[u8]) -> Result<NeuralNetwork, ModelFileError> {
+        let mut reader = Reader::new(bytes);
+        let magic = reader.take(MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(ModelFileError::UnknownFormat(
+                String::from_utf8_lossy(magic).into_owned(),
+            ));
+        }
+        let version = reader.u32()?;
+        if version > BINARY_VERSION {
+            return Err(ModelFileError::UnsupportedVersion(version));
+        }
+        let precision = match reader.u32()? {
+            4 => WeightPrecision::Single,
+            8 => WeightPrecision::Double,
+            bytes => return Err(ModelFileError::UnknownPrecision(bytes)),
+        };
+        let epochs = i32::from_le_bytes(reader.array()?);
+        let temperature = f64::from_le_bytes(reader.array()?);
+        let layers = reader.u32()? as usize;
+        check_layer_count(layers)?;
+        let mut shapes = Vec::with_capacity(layers);
+        for layer in 0..layers {
+            let shape = (reader.u32()? as usize, reader.u32()? as usize);
+            check_layer_shape(layer, shape)?;
+            shapes.push(shape);
+        }
+        let weights: usize = shapes.iter().map(|(rows, columns)| rows * columns).sum();
+        let expected = reader.position() + precision as usize * weights;
+        if bytes.len() != expected {
+            return Err(ModelFileError::InvalidLength {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        // the length is checked, so the remaining reads cannot run out of bytes
+        let mut weights = Vec::with_capacity(layers);
+        for (rows, columns) in shapes {
+            let mut data = Vec::with_capacity(rows * columns);
+            for _ in 0..(rows * columns) {
+                data.push(match precision {
+                    WeightPrecision::Single => f32::from_le_bytes(reader.array()?) as f64,
+                    WeightPrecision::Double => f64::from_le_bytes(reader.array()?),
+                });
+            }
+            weights.push(Matrix::from_flat_row_major((rows, columns), data));
+        }
+        let mut network = NeuralNetwork::from_weights(weights);
+        network.epochs = epochs;
+        network.temperature = temperature;
+        Ok(network)
+    }
+}
+
+/// Reads little endian values from some bytes, moving forward as it goes
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader::at(bytes, 0)
+    }
+
+    /// Creates a reader which starts at a position in the bytes
+    pub(crate) fn at(bytes: &'a [u8], position: usize) -> Reader<'a> {
+        Reader { bytes, position }
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    pub(crate) fn take(&mut self, length: usize) -> Result<&'a [u8], ModelFileError> {
+        let end = self.position.saturating_add(length);
+        if end > self.bytes.len() {
+            return Err(ModelFileError::InvalidLength {
+                expected: end,
+                actual: self.bytes.len(),
+            });
+        }
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], ModelFileError> {
+        Ok(self
+            .take(N)?
+            .try_into()
+            .expect("Slice has the requested length"))
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, ModelFileError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, ModelFileError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, ModelFileError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::format::tests::random_network;
+
+    fn saved_network() -> NeuralNetwork {
+        crate::format::from_json(include_str!("../worker/data/network.json"))
+            .expect("Saved network should load")
+    }
+
+    #[test]
+    fn double_precision_round_trip_matches_json() {
+        let mut network = random_network(1);
+        network.epochs = 7;
+        network.temperature = 1.0 / 3.0;
+        let from_json = crate::format::from_json(&network.to_json()).unwrap();
+        let from_bytes =
+            NeuralNetwork::read_bytes(&network.to_bytes(WeightPrecision::Double)).unwrap();
+        assert_eq!(from_bytes.weights, from_json.weights);
+        assert_eq!(from_bytes.epochs, from_json.epochs);
+        assert_eq!(
+            from_bytes.temperature.to_bits(),
+            from_json.temperature.to_bits()
+        );
+        assert_eq!(from_bytes.checksum(), from_json.checksum());
+    }
+
+    #[test]
+    fn single_precision_round_trip_rounds_weights() {
+        let network = saved_network();
+        let bytes = network.to_bytes(WeightPrecision::Single);
+        assert!(bytes.len() < network.to_bytes(WeightPrecision::Double).len());
+        let from_bytes = NeuralNetwork::read_bytes(&bytes).unwrap();
+        for (original, loaded) in network.weights.iter().zip(from_bytes.weights.iter()) {
+            for (x, y) in original.row_major_iter().zip(loaded.row_major_iter()) {
+                assert_eq!(y, x as f32 as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_out_training_history() {
+        let mut network = random_network(2);
+        network
+            .history
+            .record_epoch(1, 0.5, None, 0.1, 10.0, vec![0.5]);
+        let from_bytes =
+            NeuralNetwork::read_bytes(&network.to_bytes(WeightPrecision::Double)).unwrap();
+        assert_eq!(from_bytes.weights, network.weights);
+        assert!(from_bytes.history.is_empty());
+        assert!(from_bytes.privacy.is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_bytes() {
+        let bytes = saved_network().to_bytes(WeightPrecision::Double);
+        assert!(matches!(
+            NeuralNetwork::read_bytes(&bytes[..bytes.len() - 1]),
+            Err(ModelFileError::InvalidLength { .. })
+        ));
+    }
+}
@@ -0,0 +1,335 @@
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use std::fmt;
+
+use crate::{Dataset, Image, NeuralNetwork};
+
+/// Number of equal width confidence buckets used for the reliability diagram and ECE
+const RELIABILITY_BINS: usize = 10;
+/// The temperature search is done over log(T) between these bounds
+const MIN_TEMPERATURE: f64 = 0.05;
+const MAX_TEMPERATURE: f64 = 20.0;
+const TEMPERATURE_SEARCH_STEPS: usize = 64;
+
+pub(crate) fn default_temperature() -> f64 {
+    1.0
+}
+
+/// One bucket of a reliability diagram, counting the predictions whose confidence
+/// fell in `[lower, upper)` and how often they were actually correct.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReliabilityBin {
+    lower: f64,
+    upper: f64,
+    count: usize,
+    confidence: f64,
+    accuracy: f64,
+}
+
+/// How well the softmax probabilities of the network match its real accuracy
+/// on a dataset at a given temperature.
+#[derive(Clone, Debug, Serialize)]
+pub struct CalibrationMetrics {
+    temperature: f64,
+    negative_log_likelihood: f64,
+    expected_calibration_error: f64,
+    brier_score: f64,
+    reliability: Vec<ReliabilityBin>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CalibrationReport {
+    temperature: f64,
+    before: CalibrationMetrics,
+    after: CalibrationMetrics,
+}
+
+/// The reasons calibration can't be measured
+#[derive(Debug)]
+pub(crate) enum CalibrationError {
+    /// Every metric is an average over the dataset, so it must have images
+    EmptyDataset,
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::EmptyDataset => {
+                write!(f, "Calibration needs a dataset with at least one image")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+impl From<CalibrationError> for JsValue {
+    fn from(error: CalibrationError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// The softmax temperature the network divides its outputs by before
+    /// computing probabilities.
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    /// Returns the calibrated probability of each MBTI for an image, indexed by
+    /// the usize conversion of the MBTI.
+    pub fn probabilities(&self, image: &Image) -> Vec<f64> {
+        softmax_with_temperature(
+            &self.logits(image).row_major_iter().collect::<Vec<_>>(),
+            self.temperature,
+        )
+    }
+
+    /// Returns the calibrated probability of the MBTI `classify` would predict
+    /// for an image.
+    pub fn confidence(&self, image: &Image) -> f64 {
+        self.probabilities(image).into_iter().fold(0.0, f64::max)
+    }
+
+    /// Fits the softmax temperature which minimises the negative log likelihood of
+    /// a held out dataset, and returns the calibration metrics on that dataset from
+    /// before and after the new temperature was applied.
+    pub fn calibrate(&mut self, validation_data: &Dataset) -> Result<JsValue, JsValue> {
+        let report = self.fit_temperature(validation_data)?;
+        Ok(serde_wasm_bindgen::to_value(&report).expect("Failed to serialise calibration report"))
+    }
+
+    /// Returns the calibration metrics on a dataset at the current temperature.
+    pub fn calibration_metrics(&self, dataset: &Dataset) -> Result<JsValue, JsValue> {
+        if dataset.images.is_empty() {
+            return Err(CalibrationError::EmptyDataset.into());
+        }
+        let logits = self.dataset_logits(dataset);
+        let metrics =
+            CalibrationMetrics::new(&logits, &dataset.labels_as_indexes(), self.temperature);
+        Ok(
+            serde_wasm_bindgen::to_value(&metrics)
+                .expect("Failed to serialise calibration metrics"),
+        )
+    }
+}
+
+impl NeuralNetwork {
+    fn dataset_logits(&self, dataset: &Dataset) -> Vec<Vec<f64>> {
+        dataset
+            .images
+            .iter()
+            .map(|image| self.logits(image).row_major_iter().collect())
+            .collect()
+    }
+
+    fn fit_temperature(
+        &mut self,
+        validation_data: &Dataset,
+    ) -> Result<CalibrationReport, CalibrationError> {
+        if validation_data.images.is_empty() {
+            return Err(CalibrationError::EmptyDataset);
+        }
+        let logits = self.dataset_logits(validation_data);
+        let labels = validation_data.labels_as_indexes();
+        let before = CalibrationMetrics::new(&logits, &labels, self.temperature);
+        self.temperature = fitted_temperature(&logits, &labels);
+        let after = CalibrationMetrics::new(&logits, &labels, self.temperature);
+        Ok(CalibrationReport {
+            temperature: self.temperature,
+            before,
+            after,
+        })
+    }
+}
+
+/// Finds the temperature which minimises the negative log likelihood of the labels.
+/// The negative log likelihood is unimodal in log(T) for a fixed set of logits, so
+/// a golden section search finds the minimum without needing derivatives.
+fn fitted_temperature(logits: &[Vec<f64>], labels: &[usize]) -> f64 {
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let nll = |log_temperature: f64| negative_log_likelihood(logits, labels, log_temperature.exp());
+    let mut low = MIN_TEMPERATURE.ln();
+    let mut high = MAX_TEMPERATURE.ln();
+    let mut a = high - ratio * (high - low);
+    let mut b = low + ratio * (high - low);
+    let mut nll_a = nll(a);
+    let mut nll_b = nll(b);
+    for _ in 0..TEMPERATURE_SEARCH_STEPS {
+        if nll_a < nll_b {
+            high = b;
+            b = a;
+            nll_b = nll_a;
+            a = high - ratio * (high - low);
+            nll_a = nll(a);
+        } else {
+            low = a;
+            a = b;
+            nll_a = nll_b;
+            b = low + ratio * (high - low);
+            nll_b = nll(b);
+        }
+    }
+    ((low + high) / 2.0).exp()
+}
+
+impl Dataset {
+    fn labels_as_indexes(&self) -> Vec<usize> {
+        self.labels.iter().map(|&label| label.into()).collect()
+    }
+}
+
+impl CalibrationMetrics {
+    fn new(logits: &[Vec<f64>], labels: &[usize], temperature: f64) -> CalibrationMetrics {
+        let mut bins: Vec<ReliabilityBin> = (0..RELIABILITY_BINS)
+            .map(|i| ReliabilityBin {
+                lower: i as f64 / RELIABILITY_BINS as f64,
+                upper: (i + 1) as f64 / RELIABILITY_BINS as f64,
+                count: 0,
+                confidence: 0.0,
+                accuracy: 0.0,
+            })
+            .collect();
+        let mut brier_score = 0.0;
+        for (output, &label) in logits.iter().zip(labels.iter()) {
+            let probabilities = softmax_with_temperature(output, temperature);
+            let (prediction, confidence) = probabilities
+                .iter()
+                .cloned()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .unwrap();
+            brier_score += probabilities
+                .iter()
+                .enumerate()
+                .map(|(i, p)| {
+                    let target = if i == label { 1.0 } else { 0.0 };
+                    (p - target) * (p - target)
+                })
+                .sum::<f64>();
+            // a confidence of exactly 1 belongs in the last bucket
+            let bin = ((confidence * RELIABILITY_BINS as f64) as usize).min(RELIABILITY_BINS - 1);
+            bins[bin].count += 1;
+            bins[bin].confidence += confidence;
+            if prediction == label {
+                bins[bin].accuracy += 1.0;
+            }
+        }
+        let total = labels.len() as f64;
+        let mut expected_calibration_error = 0.0;
+        for bin in bins.iter_mut().filter(|bin| bin.count > 0) {
+            bin.confidence /= bin.count as f64;
+            bin.accuracy /= bin.count as f64;
+            expected_calibration_error +=
+                (bin.count as f64 / total) * (bin.accuracy - bin.confidence).abs();
+        }
+        CalibrationMetrics {
+            temperature,
+            negative_log_likelihood: negative_log_likelihood(logits, labels, temperature),
+            expected_calibration_error,
+            brier_score: brier_score / total,
+            reliability: bins,
+        }
+    }
+}
+
+/// Unlike easy_ml's softmax this doesn't panic on NaN logits from a network which
+/// diverged, and returns NaN probabilities instead.
+fn softmax_with_temperature(logits: &[f64], temperature: f64) -> Vec<f64> {
+    let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max) / temperature;
+    let exponentials: Vec<f64> = logits
+        .iter()
+        .map(|x| (x / temperature - max).exp())
+        .collect();
+    let sum: f64 = exponentials.iter().sum();
+    exponentials.iter().map(|x| x / sum).collect()
+}
+
+/// Averages -log(p) of the true label over the dataset, using log-sum-exp so
+/// small temperatures do not overflow.
+fn negative_log_likelihood(logits: &[Vec<f64>], labels: &[usize], temperature: f64) -> f64 {
+    let mut total = 0.0;
+    for (output, &label) in logits.iter().zip(labels.iter()) {
+        let max = output.iter().cloned().fold(f64::NEG_INFINITY, f64::max) / temperature;
+        let log_sum_exp = max
+            + output
+                .iter()
+                .map(|x| (x / temperature - max).exp())
+                .sum::<f64>()
+                .ln();
+        total += log_sum_exp - output[label] / temperature;
+    }
+    total / labels.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Logits whose softmax at a temperature of 1 gives `odds / (odds + 15)` to
+    /// `class` and `1 / (odds + 15)` to every other class
+    fn logits(class: usize, odds: f64) -> Vec<f64> {
+        let mut logits = vec![0.0; 16];
+        logits[class] = odds.ln();
+        logits
+    }
+
+    #[test]
+    fn calibrated_predictions_fit_a_temperature_of_1() {
+        // the network gives class 0 a probability of 1/4 and every other class 1/20,
+        // which is how often each label occurs
+        let logits = vec![logits(0, 5.0); 20];
+        let mut labels = vec![0; 5];
+        labels.extend(1..16);
+        let temperature = fitted_temperature(&logits, &labels);
+        assert!((temperature - 1.0).abs() < 1e-6, "{}", temperature);
+    }
+
+    #[test]
+    fn overconfident_predictions_fit_a_higher_temperature() {
+        let logits = vec![logits(0, 50.0); 20];
+        let mut labels = vec![0; 5];
+        labels.extend(1..16);
+        assert!(fitted_temperature(&logits, &labels) > 1.5);
+    }
+
+    #[test]
+    fn metrics_match_hand_computed_values() {
+        // predicts class 0 with 1/6 confidence and is right, then predicts class 1
+        // with 3/8 confidence and is wrong
+        let outputs = vec![logits(0, 3.0), logits(1, 9.0)];
+        let metrics = CalibrationMetrics::new(&outputs, &[0, 0], 1.0);
+        let first = (5.0f64 / 6.0).powi(2) + 15.0 / 324.0;
+        let second = (23.0f64 / 24.0).powi(2) + (3.0f64 / 8.0).powi(2) + 14.0 / 576.0;
+        assert!((metrics.brier_score - (first + second) / 2.0).abs() < 1e-12);
+        let ece = 0.5 * (1.0 - 1.0 / 6.0) + 0.5 * (3.0 / 8.0);
+        assert!((metrics.expected_calibration_error - ece).abs() < 1e-12);
+        assert_eq!(metrics.reliability[1].count, 1);
+        assert_eq!(metrics.reliability[1].accuracy, 1.0);
+        assert_eq!(metrics.reliability[3].count, 1);
+        assert_eq!(metrics.reliability[3].accuracy, 0.0);
+        let nll = -((1.0f64 / 6.0).ln() + (1.0f64 / 24.0).ln()) / 2.0;
+        assert!((metrics.negative_log_likelihood - nll).abs() < 1e-12);
+    }
+
+    #[test]
+    fn nan_logits_do_not_panic() {
+        let mut outputs = vec![logits(0, 3.0)];
+        outputs[0][5] = f64::NAN;
+        let metrics = CalibrationMetrics::new(&outputs, &[0], 1.0);
+        assert!(metrics.brier_score.is_nan());
+    }
+
+    #[test]
+    fn empty_dataset_is_rejected() {
+        let mut network = crate::format::tests::random_network(0);
+        assert!(matches!(
+            network.fit_temperature(&crate::tests::dataset(&[])),
+            Err(CalibrationError::EmptyDataset)
+        ));
+        assert_eq!(network.temperature, 1.0);
+    }
+}
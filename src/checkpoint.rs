@@ -0,0 +1,347 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use easy_ml::matrices::Matrix;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::NeuralNetwork;
+
+/// How many epochs can be rolled back by default
+const DEFAULT_CHECKPOINT_CAPACITY: usize = 5;
+
+/// What the best checkpoint is judged by
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointMetric {
+    /// Lowest average training loss of the epoch
+    Loss = 0,
+    /// Highest accuracy on the training dataset passed to `record_accuracy`
+    TrainingAccuracy = 1,
+    /// Highest accuracy on the test dataset passed to `record_accuracy`
+    TestAccuracy = 2,
+}
+
+impl CheckpointMetric {
+    fn is_better(&self, value: f64, best: f64) -> bool {
+        match self {
+            CheckpointMetric::Loss => value < best,
+            CheckpointMetric::TrainingAccuracy | CheckpointMetric::TestAccuracy => value > best,
+        }
+    }
+}
+
+/// A copy of the weights of the network after some number of epochs
+#[derive(Clone, Debug)]
+pub(crate) struct Checkpoint {
+    weights: Vec<Matrix<f64>>,
+    epochs: i32,
+    temperature: f64,
+}
+
+/// Copies of the network kept in memory during training, so a bad epoch can be
+/// undone. These are not serialised with the network.
+#[derive(Clone, Debug)]
+pub(crate) struct Checkpoints {
+    capacity: usize,
+    /// The network before each of the most recent epochs, oldest first
+    ring: VecDeque<Checkpoint>,
+    metric: CheckpointMetric,
+    /// The checkpoint with the best value of the metric, and that value
+    best: Option<(f64, Checkpoint)>,
+}
+
+impl Default for Checkpoints {
+    fn default() -> Self {
+        Checkpoints {
+            capacity: DEFAULT_CHECKPOINT_CAPACITY,
+            ring: VecDeque::with_capacity(DEFAULT_CHECKPOINT_CAPACITY),
+            metric: CheckpointMetric::Loss,
+            best: None,
+        }
+    }
+}
+
+/// The reasons a network can't be restored from a checkpoint
+#[derive(Debug)]
+pub(crate) enum CheckpointError {
+    NotEnoughCheckpoints { requested: usize, available: usize },
+    NoBestCheckpoint,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::NotEnoughCheckpoints {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Cannot roll back {} epochs when only {} checkpoints are kept",
+                requested, available
+            ),
+            CheckpointError::NoBestCheckpoint => {
+                write!(f, "No epoch has been measured by the checkpoint metric")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<CheckpointError> for JsValue {
+    fn from(error: CheckpointError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Sets how many of the most recent epochs can be rolled back, discarding the
+    /// oldest checkpoints if there are already more. A capacity of 0 stops
+    /// checkpointing every epoch.
+    pub fn set_checkpoint_capacity(&mut self, capacity: usize) {
+        let checkpoints = &mut self.checkpoints;
+        checkpoints.capacity = capacity;
+        while checkpoints.ring.len() > capacity {
+            checkpoints.ring.pop_front();
+        }
+    }
+
+    /// Sets what the best checkpoint is judged by, forgetting the current best
+    /// checkpoint as it was judged by a different metric.
+    pub fn set_checkpoint_metric(&mut self, metric: CheckpointMetric) {
+        self.checkpoints.metric = metric;
+        self.checkpoints.best = None;
+    }
+
+    /// The epochs the network can be rolled back to, oldest first
+    pub fn checkpoint_epochs(&self) -> Vec<i32> {
+        self.checkpoints
+            .ring
+            .iter()
+            .map(|checkpoint| checkpoint.epochs)
+            .collect()
+    }
+
+    /// The epoch of the best checkpoint, if any epoch has been measured yet
+    pub fn best_checkpoint_epoch(&self) -> Option<i32> {
+        self.checkpoints
+            .best
+            .as_ref()
+            .map(|(_, checkpoint)| checkpoint.epochs)
+    }
+
+    /// Undoes the last `epochs` epochs of training, restoring the weights from
+    /// before them and dropping them from the training history. The privacy spent
+    /// by those epochs is not refunded, as the training data was still used.
+    pub fn rollback(&mut self, epochs: usize) -> Result<(), JsValue> {
+        self.rollback_epochs(epochs).map_err(JsValue::from)
+    }
+
+    /// Restores the weights from the epoch with the best value of the checkpoint
+    /// metric, dropping any later epochs from the training history.
+    pub fn restore_best(&mut self) -> Result<(), JsValue> {
+        self.restore_best_checkpoint().map_err(JsValue::from)
+    }
+}
+
+impl NeuralNetwork {
+    pub(crate) fn rollback_epochs(&mut self, epochs: usize) -> Result<(), CheckpointError> {
+        let available = self.checkpoints.ring.len();
+        if epochs == 0 || epochs > available {
+            return Err(CheckpointError::NotEnoughCheckpoints {
+                requested: epochs,
+                available,
+            });
+        }
+        let checkpoint = self.checkpoints.ring[available - epochs].clone();
+        self.restore(checkpoint);
+        Ok(())
+    }
+
+    fn restore_best_checkpoint(&mut self) -> Result<(), CheckpointError> {
+        let (_, checkpoint) = self
+            .checkpoints
+            .best
+            .clone()
+            .ok_or(CheckpointError::NoBestCheckpoint)?;
+        self.restore(checkpoint);
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            weights: self.weights.clone(),
+            epochs: self.epochs,
+            temperature: self.temperature,
+        }
+    }
+
+    /// Keeps a copy of the network before it trains for another epoch, dropping
+    /// the oldest copy if the ring is full.
+    pub(crate) fn save_checkpoint(&mut self) {
+        if self.checkpoints.capacity == 0 {
+            return;
+        }
+        let checkpoint = self.checkpoint();
+        let ring = &mut self.checkpoints.ring;
+        if ring.len() == self.checkpoints.capacity {
+            ring.pop_front();
+        }
+        ring.push_back(checkpoint);
+    }
+
+    /// Makes the current network the best checkpoint if this value of the metric
+    /// beats the best so far. Values of other metrics are ignored, as are NaNs.
+    pub(crate) fn measure_checkpoint(&mut self, metric: CheckpointMetric, value: f64) {
+        if metric != self.checkpoints.metric || value.is_nan() {
+            return;
+        }
+        let better = match &self.checkpoints.best {
+            Some((best, _)) => metric.is_better(value, *best),
+            None => true,
+        };
+        if better {
+            self.checkpoints.best = Some((value, self.checkpoint()));
+        }
+    }
+
+    /// Replaces the weights with a checkpoint's and forgets everything that
+    /// happened after it, except the best checkpoint.
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.checkpoints
+            .ring
+            .retain(|kept| kept.epochs < checkpoint.epochs);
+        self.history.truncate(checkpoint.epochs);
+        self.weights = checkpoint.weights;
+        self.epochs = checkpoint.epochs;
+        self.temperature = checkpoint.temperature;
+        self.last_adversarial_loss = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+    use crate::{Image, HEIGHT, WIDTH};
+
+    /// Does the checkpointing and bookkeeping of an epoch of training without the
+    /// training, setting every weight to the epoch number so restores can be seen
+    fn epoch(network: &mut NeuralNetwork, loss: f64) {
+        network.save_checkpoint();
+        network.epochs += 1;
+        let epoch = network.epochs as f64;
+        for weights in network.weights.iter_mut() {
+            weights.map_mut(|_| epoch);
+        }
+        network
+            .history
+            .record_epoch(network.epochs, loss, None, 0.1, 1.0, vec![loss]);
+        network.measure_checkpoint(CheckpointMetric::Loss, loss);
+    }
+
+    /// A network trained for 5 epochs which keeps 3 checkpoints
+    fn trained_network() -> NeuralNetwork {
+        let mut network = random_network(0);
+        network.set_checkpoint_capacity(3);
+        for loss in [0.9, 0.5, 0.7, f64::NAN, 0.8] {
+            epoch(&mut network, loss);
+        }
+        network
+    }
+
+    fn history_epochs(network: &NeuralNetwork) -> usize {
+        serde_json::to_value(&network.history)
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn ring_drops_the_oldest_checkpoints() {
+        let network = trained_network();
+        assert_eq!(network.checkpoint_epochs(), vec![2, 3, 4]);
+        // the NaN loss of epoch 4 is ignored
+        assert_eq!(network.best_checkpoint_epoch(), Some(2));
+    }
+
+    #[test]
+    fn rollback_restores_earlier_epochs() {
+        let mut network = trained_network();
+        network.rollback_epochs(2).unwrap();
+        assert_eq!(network.epochs, 3);
+        assert!(network.weights[0].row_major_iter().all(|x| x == 3.0));
+        assert_eq!(network.checkpoint_epochs(), vec![2]);
+        assert_eq!(history_epochs(&network), 3);
+
+        assert!(matches!(
+            network.rollback_epochs(2),
+            Err(CheckpointError::NotEnoughCheckpoints {
+                requested: 2,
+                available: 1
+            })
+        ));
+        assert!(matches!(
+            network.rollback_epochs(0),
+            Err(CheckpointError::NotEnoughCheckpoints { .. })
+        ));
+        network.rollback_epochs(1).unwrap();
+        assert_eq!(network.epochs, 2);
+        assert!(network.checkpoint_epochs().is_empty());
+    }
+
+    #[test]
+    fn restore_best_restores_the_lowest_loss() {
+        let mut network = trained_network();
+        network.restore_best_checkpoint().unwrap();
+        assert_eq!(network.epochs, 2);
+        assert!(network.weights[0].row_major_iter().all(|x| x == 2.0));
+        assert!(network.checkpoint_epochs().is_empty());
+        assert_eq!(history_epochs(&network), 2);
+        // the best checkpoint is kept, so training can carry on from it
+        assert_eq!(network.best_checkpoint_epoch(), Some(2));
+        epoch(&mut network, 0.6);
+        assert_eq!(network.checkpoint_epochs(), vec![2]);
+        assert_eq!(network.best_checkpoint_epoch(), Some(2));
+    }
+
+    #[test]
+    fn restore_best_needs_a_measured_epoch() {
+        let mut network = random_network(1);
+        assert!(matches!(
+            network.restore_best_checkpoint(),
+            Err(CheckpointError::NoBestCheckpoint)
+        ));
+        network.set_checkpoint_metric(CheckpointMetric::TestAccuracy);
+        epoch(&mut network, 0.5);
+        assert_eq!(network.best_checkpoint_epoch(), None);
+    }
+
+    #[test]
+    fn capacity_changes_how_many_epochs_are_kept() {
+        let mut network = trained_network();
+        network.set_checkpoint_capacity(1);
+        assert_eq!(network.checkpoint_epochs(), vec![4]);
+        network.set_checkpoint_capacity(0);
+        assert!(network.checkpoint_epochs().is_empty());
+        epoch(&mut network, 0.1);
+        assert!(network.checkpoint_epochs().is_empty());
+        assert_eq!(network.best_checkpoint_epoch(), Some(6));
+    }
+
+    #[test]
+    fn diverged_weights_can_still_classify_before_rolling_back() {
+        let mut network = trained_network();
+        network.weights[1].set(0, 0, f64::NAN);
+        assert!(!network.weights_are_finite());
+        network.classify(&Image {
+            data: vec![0.5; WIDTH * HEIGHT],
+        });
+        network.rollback_epochs(1).unwrap();
+        assert!(network.weights_are_finite());
+    }
+}
@@ -0,0 +1,500 @@
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use easy_ml::matrices::Matrix;
+
+use std::cmp;
+
+use crate::{Dataset, Image, NeuralNetwork, MBTI, SECOND_HIDDEN_LAYER_SIZE};
+
+/// Iterations of the power method used to find each principal component
+const POWER_ITERATIONS: usize = 256;
+/// Iterations of t-SNE during which the attractive forces are exaggerated so
+/// clusters can form before they are spread out
+const EARLY_EXAGGERATION_ITERATIONS: usize = 250;
+const EARLY_EXAGGERATION: f64 = 12.0;
+const TSNE_LEARNING_RATE: f64 = 200.0;
+const INITIAL_MOMENTUM: f64 = 0.5;
+const FINAL_MOMENTUM: f64 = 0.8;
+const MIN_GAIN: f64 = 0.01;
+/// Attempts at the binary search for the Gaussian precision of each point which
+/// gives the requested perplexity
+const PERPLEXITY_SEARCH_STEPS: usize = 64;
+
+/// A single embedding projected to 2 dimensions, along with the true label of
+/// its image for colouring a scatter plot.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProjectedPoint {
+    x: f64,
+    y: f64,
+    label: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PrincipalComponents {
+    points: Vec<ProjectedPoint>,
+    /// The fraction of the embeddings' total variance captured by each of the
+    /// two components
+    explained_variance: Vec<f64>,
+}
+
+/// How the distance between two embeddings is measured
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceMetric {
+    Euclidean = 0,
+    /// 1 minus the cosine similarity, so identical directions have a distance of 0
+    Cosine = 1,
+}
+
+/// The last hidden layer outputs of every image in a dataset, which can be
+/// searched for the images a network considers most similar to another image.
+/// The index does not update if the network it was built from is trained further.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct EmbeddingIndex {
+    embeddings: Vec<Vec<f64>>,
+    labels: Vec<MBTI>,
+    metric: DistanceMetric,
+}
+
+/// A dataset image found by searching an EmbeddingIndex
+#[derive(Clone, Debug, Serialize)]
+pub struct Neighbour {
+    index: usize,
+    label: &'static str,
+    distance: f64,
+}
+
+#[wasm_bindgen]
+impl EmbeddingIndex {
+    pub fn len(&self) -> usize {
+        self.embeddings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.embeddings.is_empty()
+    }
+
+    /// Finds the k images in the index closest to the image under the network's
+    /// representation, returning their indexes into the indexed dataset, labels and
+    /// distances, nearest first.
+    pub fn query(&self, network: &NeuralNetwork, image: &Image, k: usize) -> JsValue {
+        let neighbours = self.nearest(&network.embedding(image), k);
+        serde_wasm_bindgen::to_value(&neighbours).expect("Failed to serialise neighbours")
+    }
+}
+
+impl EmbeddingIndex {
+    fn nearest(&self, embedding: &[f64], k: usize) -> Vec<Neighbour> {
+        let mut distances: Vec<(usize, f64)> = self
+            .embeddings
+            .iter()
+            .map(|other| match self.metric {
+                DistanceMetric::Euclidean => squared_distance(embedding, other).sqrt(),
+                DistanceMetric::Cosine => cosine_distance(embedding, other),
+            })
+            .enumerate()
+            .collect();
+        distances.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+        distances
+            .into_iter()
+            .take(k)
+            .map(|(index, distance)| Neighbour {
+                index,
+                label: self.labels[index].into(),
+                distance,
+            })
+            .collect()
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Builds an index of the last hidden layer outputs of every image in the
+    /// dataset, for finding the images most similar to a query image.
+    pub fn embedding_index(&self, dataset: &Dataset, metric: DistanceMetric) -> EmbeddingIndex {
+        EmbeddingIndex {
+            embeddings: self.dataset_embeddings(dataset),
+            labels: dataset.labels.clone(),
+            metric,
+        }
+    }
+
+    /// Returns the 64 outputs of the last hidden layer for every image in the
+    /// dataset, flattened in row major order with one row per image.
+    pub fn embeddings(&self, dataset: &Dataset) -> Vec<f64> {
+        self.dataset_embeddings(dataset).concat()
+    }
+
+    /// Projects the last hidden layer outputs of every image in the dataset onto
+    /// their first two principal components.
+    pub fn pca_projection(&self, dataset: &Dataset) -> JsValue {
+        let embeddings = self.dataset_embeddings(dataset);
+        let (coordinates, explained_variance) = pca(&embeddings);
+        let projection = PrincipalComponents {
+            points: points(&coordinates, &dataset.labels),
+            explained_variance,
+        };
+        serde_wasm_bindgen::to_value(&projection).expect("Failed to serialise projection")
+    }
+
+    /// Projects the last hidden layer outputs of every image in the dataset to 2
+    /// dimensions with t-SNE, starting from the PCA projection. Each iteration
+    /// compares every pair of images, so this is best run on the smaller test
+    /// dataset.
+    pub fn tsne_projection(
+        &self,
+        dataset: &Dataset,
+        perplexity: f64,
+        iterations: usize,
+    ) -> JsValue {
+        assert!(perplexity >= 1.0, "Perplexity must be at least 1");
+        let embeddings = self.dataset_embeddings(dataset);
+        let coordinates = tsne(&embeddings, perplexity, iterations);
+        serde_wasm_bindgen::to_value(&points(&coordinates, &dataset.labels))
+            .expect("Failed to serialise projection")
+    }
+}
+
+impl NeuralNetwork {
+    /// Returns the output of the last hidden layer for an image
+    pub(crate) fn embedding(&self, image: &Image) -> Vec<f64> {
+        self.feedforward(image).1.row_major_iter().collect()
+    }
+
+    pub(crate) fn dataset_embeddings(&self, dataset: &Dataset) -> Vec<Vec<f64>> {
+        dataset
+            .images
+            .iter()
+            .map(|image| self.embedding(image))
+            .collect()
+    }
+}
+
+fn points(coordinates: &[(f64, f64)], labels: &[MBTI]) -> Vec<ProjectedPoint> {
+    coordinates
+        .iter()
+        .zip(labels.iter())
+        .map(|(&(x, y), &label)| ProjectedPoint {
+            x,
+            y,
+            label: label.into(),
+        })
+        .collect()
+}
+
+/// Finds the first two principal components of the embeddings with the power
+/// method, returning each embedding projected onto them and the fraction of the
+/// total variance each component explains.
+fn pca(embeddings: &[Vec<f64>]) -> (Vec<(f64, f64)>, Vec<f64>) {
+    let samples = embeddings.len();
+    if samples == 0 {
+        return (Vec::new(), vec![0.0, 0.0]);
+    }
+    let dimensions = SECOND_HIDDEN_LAYER_SIZE;
+    let mut means = vec![0.0; dimensions];
+    for embedding in embeddings {
+        for (mean, x) in means.iter_mut().zip(embedding.iter()) {
+            *mean += x / samples as f64;
+        }
+    }
+    let centered = Matrix::from_flat_row_major(
+        (samples, dimensions),
+        embeddings
+            .iter()
+            .flat_map(|embedding| embedding.iter().zip(means.iter()).map(|(x, mean)| x - mean))
+            .collect(),
+    );
+    let mut covariance =
+        (centered.transpose() * &centered).map(|x| x / cmp::max(samples - 1, 1) as f64);
+    let total_variance: f64 = (0..dimensions).map(|i| covariance.get(i, i)).sum();
+    let mut components = Vec::with_capacity(2);
+    let mut explained_variance = Vec::with_capacity(2);
+    for _ in 0..2 {
+        // start from a vector that is unlikely to be orthogonal to the top component
+        let mut vector = Matrix::from_flat_row_major(
+            (dimensions, 1),
+            (0..dimensions)
+                .map(|i| 1.0 + i as f64 / dimensions as f64)
+                .collect(),
+        );
+        let mut eigenvalue = 0.0;
+        for _ in 0..POWER_ITERATIONS {
+            let next = &covariance * &vector;
+            let norm = next.row_major_iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm == 0.0 {
+                break;
+            }
+            eigenvalue = norm;
+            vector = next.map(|x| x / norm);
+        }
+        // deflate so the next power method finds the following component
+        let outer = &vector * vector.transpose();
+        covariance = covariance - outer.map(|x| x * eigenvalue);
+        explained_variance.push(if total_variance > 0.0 {
+            eigenvalue / total_variance
+        } else {
+            0.0
+        });
+        components.push(vector);
+    }
+    let projected = (&centered * &components[0], &centered * &components[1]);
+    let coordinates = (0..samples)
+        .map(|i| (projected.0.get(i, 0), projected.1.get(i, 0)))
+        .collect();
+    (coordinates, explained_variance)
+}
+
+fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+fn cosine_distance(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norms =
+        a.iter().map(|x| x * x).sum::<f64>().sqrt() * b.iter().map(|y| y * y).sum::<f64>().sqrt();
+    if norms == 0.0 {
+        return 1.0;
+    }
+    1.0 - dot / norms
+}
+
+/// Computes the conditional probabilities p(j|i) that each point i would pick
+/// each of its nearest neighbours j, using a Gaussian whose width is chosen so
+/// the distribution has the requested perplexity. Only the 3 * perplexity
+/// nearest neighbours are kept, as the rest are vanishingly small.
+fn conditional_probabilities(embeddings: &[Vec<f64>], perplexity: f64) -> Vec<Vec<(usize, f64)>> {
+    let samples = embeddings.len();
+    let neighbours = cmp::min(samples - 1, (3.0 * perplexity).ceil() as usize);
+    let target_entropy = perplexity.ln();
+    let mut probabilities = Vec::with_capacity(samples);
+    for i in 0..samples {
+        let mut distances: Vec<(usize, f64)> = (0..samples)
+            .filter(|&j| j != i)
+            .map(|j| (j, squared_distance(&embeddings[i], &embeddings[j])))
+            .collect();
+        if neighbours < distances.len() {
+            distances.select_nth_unstable_by(neighbours, |(_, a), (_, b)| a.total_cmp(b));
+            distances.truncate(neighbours);
+        }
+        // subtract the smallest distance before exponentiating to avoid underflow
+        let nearest = distances
+            .iter()
+            .map(|&(_, d)| d)
+            .fold(f64::INFINITY, f64::min);
+        let mut beta = 1.0;
+        let mut low = 0.0;
+        let mut high = f64::INFINITY;
+        let mut row = vec![0.0; distances.len()];
+        for _ in 0..PERPLEXITY_SEARCH_STEPS {
+            let mut sum = 0.0;
+            for (p, &(_, d)) in row.iter_mut().zip(distances.iter()) {
+                *p = (-(d - nearest) * beta).exp();
+                sum += *p;
+            }
+            let mut entropy = 0.0;
+            for (p, &(_, d)) in row.iter_mut().zip(distances.iter()) {
+                *p /= sum;
+                entropy += beta * (d - nearest) * *p;
+            }
+            entropy += sum.ln();
+            if (entropy - target_entropy).abs() < 1e-5 {
+                break;
+            }
+            // a higher precision narrows the Gaussian and lowers the entropy
+            if entropy > target_entropy {
+                low = beta;
+                beta = if high.is_infinite() {
+                    beta * 2.0
+                } else {
+                    (beta + high) / 2.0
+                };
+            } else {
+                high = beta;
+                beta = (beta + low) / 2.0;
+            }
+        }
+        probabilities.push(
+            distances
+                .iter()
+                .zip(row.iter())
+                .map(|(&(j, _), &p)| (j, p))
+                .collect(),
+        );
+    }
+    probabilities
+}
+
+/// Embeds the points in 2 dimensions with t-SNE, using exact repulsive forces and
+/// attractive forces between each point's nearest neighbours.
+fn tsne(embeddings: &[Vec<f64>], perplexity: f64, iterations: usize) -> Vec<(f64, f64)> {
+    let samples = embeddings.len();
+    if samples < 2 {
+        return vec![(0.0, 0.0); samples];
+    }
+    let probabilities = conditional_probabilities(embeddings, perplexity);
+    // start from a tiny copy of the PCA projection so the result is deterministic
+    let (initial, _) = pca(embeddings);
+    let spread = (initial.iter().map(|(x, _)| x * x).sum::<f64>() / samples as f64).sqrt();
+    let scale = if spread > 0.0 { 1e-4 / spread } else { 1e-4 };
+    let mut positions: Vec<[f64; 2]> = initial
+        .iter()
+        .map(|&(x, y)| [x * scale, y * scale])
+        .collect();
+    let mut velocities = vec![[0.0; 2]; samples];
+    let mut gains = vec![[1.0; 2]; samples];
+    for iteration in 0..iterations {
+        let exaggeration = if iteration < EARLY_EXAGGERATION_ITERATIONS {
+            EARLY_EXAGGERATION
+        } else {
+            1.0
+        };
+        let momentum = if iteration < EARLY_EXAGGERATION_ITERATIONS {
+            INITIAL_MOMENTUM
+        } else {
+            FINAL_MOMENTUM
+        };
+        let mut attractive = vec![[0.0; 2]; samples];
+        for (i, row) in probabilities.iter().enumerate() {
+            for &(j, p) in row {
+                // the joint probability is the average of p(j|i) and p(i|j), so each
+                // conditional pulls both points towards each other
+                let weight = exaggeration * p / (2.0 * samples as f64);
+                let difference = [
+                    positions[i][0] - positions[j][0],
+                    positions[i][1] - positions[j][1],
+                ];
+                let q = 1.0 / (1.0 + difference[0] * difference[0] + difference[1] * difference[1]);
+                for d in 0..2 {
+                    attractive[i][d] += weight * q * difference[d];
+                    attractive[j][d] -= weight * q * difference[d];
+                }
+            }
+        }
+        let mut repulsive = vec![[0.0; 2]; samples];
+        let mut normalisation = 0.0;
+        for i in 0..samples {
+            for j in (i + 1)..samples {
+                let difference = [
+                    positions[i][0] - positions[j][0],
+                    positions[i][1] - positions[j][1],
+                ];
+                let q = 1.0 / (1.0 + difference[0] * difference[0] + difference[1] * difference[1]);
+                normalisation += 2.0 * q;
+                for d in 0..2 {
+                    repulsive[i][d] += q * q * difference[d];
+                    repulsive[j][d] -= q * q * difference[d];
+                }
+            }
+        }
+        for i in 0..samples {
+            for d in 0..2 {
+                let gradient = 4.0 * (attractive[i][d] - repulsive[i][d] / normalisation);
+                // grow the step size while the gradient keeps its direction
+                gains[i][d] = if (gradient > 0.0) != (velocities[i][d] > 0.0) {
+                    gains[i][d] + 0.2
+                } else {
+                    f64::max(gains[i][d] * 0.8, MIN_GAIN)
+                };
+                velocities[i][d] =
+                    momentum * velocities[i][d] - TSNE_LEARNING_RATE * gains[i][d] * gradient;
+                positions[i][d] += velocities[i][d];
+            }
+        }
+    }
+    positions.iter().map(|&[x, y]| (x, y)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+    use crate::{SeededRandomGenerator, HEIGHT, WIDTH};
+
+    /// Embeddings spread along the first dimension with a little uncorrelated
+    /// spread along the second, so the principal axes are known in advance
+    fn elongated_embeddings() -> Vec<Vec<f64>> {
+        (0..20)
+            .map(|i| {
+                let mut embedding = vec![0.5; SECOND_HIDDEN_LAYER_SIZE];
+                embedding[0] += (i as f64 - 9.5) * 2.0;
+                embedding[1] += if i % 4 == 0 || i % 4 == 3 { 0.1 } else { -0.1 };
+                embedding
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pca_finds_the_axis_of_greatest_variance() {
+        let embeddings = elongated_embeddings();
+        let (coordinates, explained_variance) = pca(&embeddings);
+        assert_eq!(coordinates.len(), embeddings.len());
+        assert!(explained_variance[0] > 0.99);
+        assert!(explained_variance[0] + explained_variance[1] > 0.999999);
+        // the first component is the first dimension, up to its sign
+        let sign = coordinates[0].0.signum();
+        for (embedding, &(x, y)) in embeddings.iter().zip(coordinates.iter()) {
+            assert!((x - sign * -(embedding[0] - 0.5)).abs() < 1e-6);
+            assert!((y.abs() - 0.1).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn pca_of_nothing_is_empty() {
+        let (coordinates, explained_variance) = pca(&[]);
+        assert!(coordinates.is_empty());
+        assert_eq!(explained_variance, vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn tsne_keeps_clusters_apart() {
+        // two tight clusters far apart in the embedding space
+        let embeddings: Vec<Vec<f64>> = (0..20)
+            .map(|i| {
+                let mut embedding = vec![0.0; SECOND_HIDDEN_LAYER_SIZE];
+                embedding[0] = if i < 10 { 0.0 } else { 10.0 };
+                embedding[1] = (i % 10) as f64 * 0.01;
+                embedding
+            })
+            .collect();
+        let coordinates = tsne(&embeddings, 5.0, 300);
+        assert_eq!(coordinates.len(), embeddings.len());
+        assert!(coordinates
+            .iter()
+            .all(|(x, y)| x.is_finite() && y.is_finite()));
+        let distance =
+            |a: (f64, f64), b: (f64, f64)| ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt();
+        let within = distance(coordinates[0], coordinates[9]);
+        let between = distance(coordinates[0], coordinates[10]);
+        assert!(between > within, "{} should exceed {}", between, within);
+    }
+
+    #[test]
+    fn an_indexed_image_is_its_own_nearest_neighbour() {
+        let network = random_network(3);
+        let mut random = SeededRandomGenerator::new(4);
+        let dataset = Dataset {
+            images: (0..12)
+                .map(|_| Image {
+                    data: (0..WIDTH * HEIGHT).map(|_| random.uniform()).collect(),
+                })
+                .collect(),
+            labels: (0..12).map(MBTI::from).collect(),
+        };
+        for metric in [DistanceMetric::Euclidean, DistanceMetric::Cosine] {
+            let index = network.embedding_index(&dataset, metric);
+            for (i, image) in dataset.images.iter().enumerate() {
+                let neighbours = index.nearest(&network.embedding(image), 5);
+                assert_eq!(neighbours.len(), 5);
+                assert_eq!(neighbours[0].index, i);
+                assert_eq!(neighbours[0].label, <&str>::from(dataset.labels[i]));
+                assert!(neighbours[0].distance.abs() < 1e-9);
+                assert!(neighbours
+                    .windows(2)
+                    .all(|pair| pair[0].distance <= pair[1].distance));
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use wasm_bindgen::prelude::*;
+
+use easy_ml::differentiation::{Record, WengertList};
+use easy_ml::matrices::Matrix;
+
+use std::cmp;
+
+use crate::{sigmoid, Image, NeuralNetwork, Pixel, HEIGHT, MBTI, WIDTH};
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Computes a saliency map for the MBTI the network predicts for an image.
+    /// See `explain_class`.
+    pub fn explain(&self, image: &Image) -> Vec<f64> {
+        self.explain_class(image, self.classify(image))
+    }
+
+    /// Computes a saliency map of how sensitive the network's output for the given
+    /// MBTI is to each pixel of the image, returned as 16x16 row major values in the
+    /// 0-1 range so it can be drawn over the heatmap like any other image.
+    pub fn explain_class(&self, image: &Image, label: MBTI) -> Vec<f64> {
+        let gradient = self.class_gradient(&image.data, usize::from(label));
+        let saliency: Vec<f64> = gradient.iter().map(|x| x.abs()).collect();
+        let max = saliency.iter().cloned().fold(0.0, f64::max);
+        if max == 0.0 {
+            return saliency;
+        }
+        saliency.iter().map(|x| x / max).collect()
+    }
+
+    /// Attributes the network's output for the given MBTI to each pixel of the image
+    /// by integrating the gradient along the straight line path from the baseline
+    /// image to the image, approximated with a Riemann sum over `steps` points.
+    /// Returns 16x16 row major signed attributions which approximately sum to the
+    /// difference in output between the image and the baseline.
+    pub fn integrated_gradients(
+        &self,
+        image: &Image,
+        baseline: &Image,
+        label: MBTI,
+        steps: usize,
+    ) -> Vec<f64> {
+        assert!(steps > 0, "At least one step is needed");
+        let class = usize::from(label);
+        let mut total = vec![0.0; WIDTH * HEIGHT];
+        for step in 1..=steps {
+            let alpha = step as f64 / steps as f64;
+            let pixels: Vec<f64> = baseline
+                .data
+                .iter()
+                .zip(image.data.iter())
+                .map(|(b, x)| b + alpha * (x - b))
+                .collect();
+            for (sum, gradient) in total.iter_mut().zip(self.class_gradient(&pixels, class)) {
+                *sum += gradient;
+            }
+        }
+        total
+            .iter()
+            .zip(image.data.iter().zip(baseline.data.iter()))
+            .map(|(sum, (x, b))| (x - b) * sum / steps as f64)
+            .collect()
+    }
+
+    /// Attributes the network's output for the given MBTI to each pixel of the image
+    /// by sliding a `patch_size` by `patch_size` square filled with `fill` over the
+    /// image and measuring how much the probability of the MBTI drops each time.
+    /// Each pixel is given the average drop over every patch which covered it,
+    /// returned as 16x16 row major values. Positive values mean hiding the pixel
+    /// made the network less sure of the MBTI.
+    pub fn occlusion_sensitivity(
+        &self,
+        image: &Image,
+        label: MBTI,
+        patch_size: usize,
+        fill: f64,
+    ) -> Vec<f64> {
+        assert!(
+            patch_size > 0 && patch_size <= cmp::min(WIDTH, HEIGHT),
+            "Patch must fit inside the image"
+        );
+        let class = usize::from(label);
+        let original = self.probabilities(image)[class];
+        let mut drops = vec![0.0; WIDTH * HEIGHT];
+        let mut coverage = vec![0; WIDTH * HEIGHT];
+        for top in 0..=(HEIGHT - patch_size) {
+            for left in 0..=(WIDTH - patch_size) {
+                let mut occluded = image.clone();
+                for y in top..(top + patch_size) {
+                    for x in left..(left + patch_size) {
+                        occluded.data[y * WIDTH + x] = fill;
+                    }
+                }
+                let drop = original - self.probabilities(&occluded)[class];
+                for y in top..(top + patch_size) {
+                    for x in left..(left + patch_size) {
+                        drops[y * WIDTH + x] += drop;
+                        coverage[y * WIDTH + x] += 1;
+                    }
+                }
+            }
+        }
+        drops
+            .iter()
+            .zip(coverage.iter())
+            .map(|(drop, &count)| drop / count as f64)
+            .collect()
+    }
+}
+
+#[wasm_bindgen]
+impl Image {
+    /// Creates an Image with every pixel set to the same value, such as a black
+    /// baseline for `integrated_gradients`.
+    pub fn filled(value: Pixel) -> Image {
+        Image {
+            data: vec![value; WIDTH * HEIGHT],
+        }
+    }
+}
+
+impl NeuralNetwork {
+    /// Differentiates the pre softmax output for a class with respect to each of the
+    /// WIDTH * HEIGHT input pixels.
+    pub(crate) fn class_gradient(&self, pixels: &[f64], class: usize) -> Vec<f64> {
+        self.input_gradient(pixels, |output| output.get(0, class))
+    }
+
+    /// Differentiates a score computed from the 1x16 pre softmax output with respect
+    /// to each of the WIDTH * HEIGHT input pixels. Unlike training, the weights are
+    /// held constant and the input pixels are the variables.
+    pub(crate) fn input_gradient<F>(&self, pixels: &[f64], score: F) -> Vec<f64>
+    where
+        F: for<'a> Fn(&Matrix<Record<'a, f64>>) -> Record<'a, f64>,
+    {
+        let history = WengertList::new();
+        let input = Matrix::from_flat_row_major(
+            (1, WIDTH * HEIGHT),
+            pixels
+                .iter()
+                .map(|&pixel| Record::variable(pixel, &history))
+                .collect(),
+        );
+        let weights: Vec<Matrix<Record<f64>>> = self
+            .weights
+            .iter()
+            .map(|layer| layer.map(Record::constant))
+            .collect();
+        let output = {
+            let layer1 = (&input * &weights[0]).map(sigmoid);
+            let layer2 = (layer1 * &weights[1]).map(sigmoid);
+            layer2 * &weights[2]
+        };
+        let derivatives = score(&output).derivatives();
+        input
+            .row_major_iter()
+            .map(|pixel| derivatives[&pixel])
+            .collect()
+    }
+}
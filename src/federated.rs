@@ -0,0 +1,568 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use easy_ml::matrices::Matrix;
+
+use std::fmt;
+
+use crate::checkpoint::CheckpointMetric;
+use crate::format::{check_layer_count, check_layer_shape, validate_weights, ModelFileError};
+use crate::privacy::PrivacyBudget;
+use crate::{learning_rate, now, Dataset, NeuralNetwork, SeededRandomGenerator};
+
+/// The change a client made to the global network by training it on local data,
+/// which is all a client shares with the coordinator.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelUpdate {
+    /// The content hash of the global network the client started from
+    base: String,
+    /// How many training images the client trained on
+    samples: u32,
+    /// How many epochs the client trained for
+    epochs: i32,
+    /// The trained weights minus the global weights, for each layer
+    deltas: Vec<Matrix<f64>>,
+}
+
+/// The reasons a federated update can be rejected
+#[derive(Debug)]
+pub(crate) enum FederatedError {
+    /// The update was computed from a different global network
+    BaseMismatch {
+        expected: String,
+        actual: String,
+    },
+    NoUpdates,
+    NoSamples,
+    NoClients,
+    EmptyDataset,
+    Model(ModelFileError),
+}
+
+impl fmt::Display for FederatedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FederatedError::BaseMismatch { expected, actual } => write!(
+                f,
+                "Update is for global network {} but this round is for {}",
+                actual, expected
+            ),
+            FederatedError::NoUpdates => write!(f, "No client updates have been added"),
+            FederatedError::NoSamples => write!(f, "Client updates must have trained on samples"),
+            FederatedError::NoClients => write!(f, "There must be at least one client"),
+            FederatedError::EmptyDataset => {
+                write!(f, "The dataset has no images to split between clients")
+            }
+            FederatedError::Model(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for FederatedError {}
+
+impl From<ModelFileError> for FederatedError {
+    fn from(error: ModelFileError) -> Self {
+        FederatedError::Model(error)
+    }
+}
+
+impl From<FederatedError> for JsValue {
+    fn from(error: FederatedError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+#[wasm_bindgen]
+impl ModelUpdate {
+    /// The content hash of the global network the update was computed from
+    pub fn base_hash(&self) -> String {
+        self.base.clone()
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Serialises the update as a JSON message for sending to the coordinator
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialise model update")
+    }
+
+    /// Deserialises an update message, checking each delta has the shape of its
+    /// layer and the data to fill it
+    pub fn from_json(json: &str) -> Result<ModelUpdate, JsValue> {
+        ModelUpdate::read_json(json).map_err(JsValue::from)
+    }
+}
+
+impl ModelUpdate {
+    fn read_json(json: &str) -> Result<ModelUpdate, ModelFileError> {
+        let value: Value = serde_json::from_str(json)?;
+        validate_weights(value.get("deltas"))?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Flattens the update into every delta multiplied by the number of samples,
+    /// in row major order layer by layer, followed by the number of samples. The
+    /// sum of these vectors over many clients is all FedAvg needs to know.
+    pub(crate) fn weighted_values(&self) -> Vec<f64> {
+        let samples = self.samples as f64;
+        self.deltas
+            .iter()
+            .flat_map(|delta| delta.row_major_iter())
+            .map(|x| x * samples)
+            .chain(std::iter::once(samples))
+            .collect()
+    }
+
+    /// Creates the average update of many clients from the sum of their
+    /// `weighted_values`, so it can be added to the global network on its own.
+    pub(crate) fn from_weighted_sum(
+        base: &NeuralNetwork,
+        sum: &[f64],
+        epochs: i32,
+    ) -> Result<ModelUpdate, ModelFileError> {
+        let parameters: usize = base.weights.iter().map(|w| w.size().0 * w.size().1).sum();
+        if sum.len() != parameters + 1 {
+            return Err(ModelFileError::InvalidLength {
+                expected: parameters + 1,
+                actual: sum.len(),
+            });
+        }
+        let samples = sum[parameters].round();
+        let mut values = sum[..parameters].iter().map(|x| x / samples);
+        Ok(ModelUpdate {
+            base: base.checksum(),
+            samples: samples as u32,
+            epochs,
+            deltas: base
+                .weights
+                .iter()
+                .map(|weights| {
+                    let (rows, columns) = weights.size();
+                    Matrix::from_flat_row_major(
+                        (rows, columns),
+                        values.by_ref().take(rows * columns).collect(),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), ModelFileError> {
+        check_layer_count(self.deltas.len())?;
+        for (layer, delta) in self.deltas.iter().enumerate() {
+            check_layer_shape(layer, delta.size())?;
+        }
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Computes the update a client sends after training this network from the
+    /// global network `base` on `samples` images.
+    pub fn update_since(&self, base: &NeuralNetwork, samples: u32) -> ModelUpdate {
+        ModelUpdate {
+            base: base.checksum(),
+            samples,
+            epochs: self.epochs - base.epochs,
+            deltas: self
+                .weights
+                .iter()
+                .zip(base.weights.iter())
+                .map(|(trained, global)| trained - global)
+                .collect(),
+        }
+    }
+
+    /// Simulates federated learning by splitting a dataset evenly at random
+    /// between `clients` virtual clients. Each round every client trains a copy of
+    /// this network on its own images for `local_epochs`, and this network is
+    /// replaced by the federated average of their updates. Returns the average
+    /// training loss of each client in each round.
+    pub fn simulate_federated(
+        &mut self,
+        dataset: &Dataset,
+        clients: usize,
+        rounds: usize,
+        local_epochs: usize,
+        seed: u32,
+    ) -> Result<JsValue, JsValue> {
+        let losses = self
+            .federated_rounds(dataset, clients, rounds, local_epochs, seed as u64)
+            .map_err(JsValue::from)?;
+        Ok(serde_wasm_bindgen::to_value(&losses).expect("Failed to serialise federated losses"))
+    }
+}
+
+impl NeuralNetwork {
+    fn federated_rounds(
+        &mut self,
+        dataset: &Dataset,
+        clients: usize,
+        rounds: usize,
+        local_epochs: usize,
+        seed: u64,
+    ) -> Result<Vec<Vec<f64>>, FederatedError> {
+        let shards = dataset.split(clients, seed)?;
+        let mut losses = Vec::with_capacity(rounds);
+        for _ in 0..rounds {
+            let start = now();
+            let mut round = FederatedAverage::new(self);
+            let (updates, round_losses, privacy) = self.train_clients(&shards, local_epochs);
+            for update in updates.iter() {
+                round.add_update(update)?;
+            }
+            self.finish_round(&round, &round_losses, privacy, now() - start)?;
+            losses.push(round_losses);
+        }
+        Ok(losses)
+    }
+
+    /// Replaces the weights with the federated average of a round, keeping a
+    /// checkpoint from before the round so it can be rolled back. The round is
+    /// recorded in the training history as a single entry, with the average of
+    /// the final losses of the clients which were averaged and no batch losses.
+    pub(crate) fn finish_round(
+        &mut self,
+        round: &FederatedAverage,
+        losses: &[f64],
+        privacy: Option<PrivacyBudget>,
+        wall_time: f64,
+    ) -> Result<(), FederatedError> {
+        let averaged = round.average()?;
+        self.save_checkpoint();
+        let learning_rate = learning_rate(self.epochs);
+        self.weights = averaged.weights;
+        self.epochs = averaged.epochs;
+        self.privacy = privacy;
+        let loss = losses.iter().sum::<f64>() / losses.len() as f64;
+        self.history.record_epoch(
+            self.epochs,
+            loss,
+            None,
+            learning_rate,
+            wall_time,
+            Vec::new(),
+        );
+        self.measure_checkpoint(CheckpointMetric::Loss, loss);
+        Ok(())
+    }
+
+    /// Trains a copy of this network on each non empty shard of a dataset for
+    /// `local_epochs`, returning the update and final training loss of each, and
+    /// the privacy budget the averaged network spends, see `PrivacyBudget::largest`.
+    pub(crate) fn train_clients(
+        &self,
+        shards: &[Dataset],
+        local_epochs: usize,
+    ) -> (Vec<ModelUpdate>, Vec<f64>, Option<PrivacyBudget>) {
+        let mut updates = Vec::with_capacity(shards.len());
+        let mut losses = Vec::with_capacity(shards.len());
+        let mut budgets = Vec::with_capacity(shards.len());
+        for shard in shards.iter().filter(|shard| !shard.images.is_empty()) {
+            let mut client = self.clone();
+            let mut loss = 0.0;
+            for _ in 0..local_epochs {
+                loss = client.train_without_saving(shard).clean;
+            }
+            updates.push(client.update_since(self, shard.images.len() as u32));
+            losses.push(loss);
+            budgets.push(client.privacy);
+        }
+        (updates, losses, PrivacyBudget::largest(budgets))
+    }
+}
+
+/// Merges client updates into a new global network with FedAvg, weighting each
+/// client's change by the number of images it trained on.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct FederatedAverage {
+    global: NeuralNetwork,
+    base: String,
+    /// The sum of every delta multiplied by its number of samples
+    weighted_deltas: Vec<Matrix<f64>>,
+    samples: u64,
+    epochs: i32,
+    updates: u32,
+}
+
+#[wasm_bindgen]
+impl FederatedAverage {
+    /// Starts a round of federated averaging of updates to the global network
+    pub fn new(global: &NeuralNetwork) -> FederatedAverage {
+        FederatedAverage {
+            global: global.clone(),
+            base: global.checksum(),
+            weighted_deltas: global
+                .weights
+                .iter()
+                .map(|weights| Matrix::empty(0.0, weights.size()))
+                .collect(),
+            samples: 0,
+            epochs: 0,
+            updates: 0,
+        }
+    }
+
+    /// Adds a client's update to the round, rejecting updates which were not
+    /// computed from this round's global network.
+    pub fn add(&mut self, update: &ModelUpdate) -> Result<(), JsValue> {
+        self.add_update(update).map_err(JsValue::from)
+    }
+
+    /// The number of updates added so far
+    pub fn updates(&self) -> u32 {
+        self.updates
+    }
+
+    /// Creates the new global network, adding the sample weighted average of
+    /// every update to the global weights. The round is not recorded in the
+    /// training history, as only the clients know their losses.
+    pub fn finish(&self) -> Result<NeuralNetwork, JsValue> {
+        self.average().map_err(JsValue::from)
+    }
+}
+
+impl FederatedAverage {
+    pub(crate) fn add_update(&mut self, update: &ModelUpdate) -> Result<(), FederatedError> {
+        if update.base != self.base {
+            return Err(FederatedError::BaseMismatch {
+                expected: self.base.clone(),
+                actual: update.base.clone(),
+            });
+        }
+        if update.samples == 0 {
+            return Err(FederatedError::NoSamples);
+        }
+        update.validate()?;
+        let samples = update.samples as f64;
+        for (sum, delta) in self.weighted_deltas.iter_mut().zip(update.deltas.iter()) {
+            *sum = &*sum + delta.map(|x| x * samples);
+        }
+        self.samples += update.samples as u64;
+        self.epochs = self.epochs.max(update.epochs);
+        self.updates += 1;
+        Ok(())
+    }
+
+    pub(crate) fn average(&self) -> Result<NeuralNetwork, FederatedError> {
+        if self.updates == 0 {
+            return Err(FederatedError::NoUpdates);
+        }
+        let total = self.samples as f64;
+        let mut network = self.global.clone();
+        for (weights, sum) in network.weights.iter_mut().zip(self.weighted_deltas.iter()) {
+            *weights = &*weights + sum.map(|x| x / total);
+        }
+        network.epochs += self.epochs;
+        Ok(network)
+    }
+}
+
+impl Dataset {
+    /// Splits the dataset into parts of as equal size as possible, assigning
+    /// images to parts in a random order reproducible from the seed.
+    pub(crate) fn split(&self, parts: usize, seed: u64) -> Result<Vec<Dataset>, FederatedError> {
+        if parts == 0 {
+            return Err(FederatedError::NoClients);
+        }
+        if self.images.is_empty() {
+            return Err(FederatedError::EmptyDataset);
+        }
+        let mut random = SeededRandomGenerator::new(seed);
+        let mut order: Vec<(usize, f64)> = (0..self.images.len())
+            .map(|i| (i, random.uniform()))
+            .collect();
+        order.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+        let mut datasets: Vec<Dataset> = (0..parts)
+            .map(|_| Dataset {
+                images: Vec::new(),
+                labels: Vec::new(),
+            })
+            .collect();
+        for (position, (index, _)) in order.into_iter().enumerate() {
+            let dataset = &mut datasets[position % parts];
+            dataset.images.push(self.images[index].clone());
+            dataset.labels.push(self.labels[index]);
+        }
+        Ok(datasets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+    use crate::{Image, HEIGHT, MBTI, WIDTH};
+
+    fn dataset(images: usize) -> Dataset {
+        Dataset {
+            images: (0..images)
+                .map(|i| Image {
+                    data: vec![i as f64; WIDTH * HEIGHT],
+                })
+                .collect(),
+            labels: (0..images).map(|i| MBTI::from(i % 16)).collect(),
+        }
+    }
+
+    #[test]
+    fn update_is_the_change_from_the_base() {
+        let base = random_network(0);
+        let mut trained = random_network(1);
+        trained.epochs = base.epochs + 2;
+        let update = trained.update_since(&base, 10);
+        assert_eq!(update.base, base.checksum());
+        assert_eq!(update.epochs, 2);
+        assert_eq!(update.samples, 10);
+        for ((delta, trained), base) in update
+            .deltas
+            .iter()
+            .zip(trained.weights.iter())
+            .zip(base.weights.iter())
+        {
+            assert_eq!(delta, &(trained - base));
+        }
+    }
+
+    #[test]
+    fn update_round_trips_through_json() {
+        let base = random_network(2);
+        let update = random_network(3).update_since(&base, 7);
+        let loaded = ModelUpdate::read_json(&update.to_json()).unwrap();
+        assert_eq!(loaded.base, update.base);
+        assert_eq!(loaded.samples, 7);
+        assert_eq!(loaded.deltas, update.deltas);
+    }
+
+    #[test]
+    fn update_with_missing_data_is_rejected() {
+        let update = random_network(4).update_since(&random_network(5), 1);
+        let mut value = serde_json::to_value(&update).unwrap();
+        value["deltas"][1]["data"].as_array_mut().unwrap().pop();
+        assert!(matches!(
+            ModelUpdate::read_json(&value.to_string()),
+            Err(ModelFileError::MalformedLayer { layer: 1 })
+        ));
+    }
+
+    #[test]
+    fn average_weights_updates_by_samples() {
+        let global = random_network(6);
+        let first = random_network(7).update_since(&global, 1);
+        let second = random_network(8).update_since(&global, 3);
+        let mut round = FederatedAverage::new(&global);
+        round.add_update(&first).unwrap();
+        round.add_update(&second).unwrap();
+        assert_eq!(round.updates(), 2);
+        let averaged = round.average().unwrap();
+        for (layer, weights) in averaged.weights.iter().enumerate() {
+            let expected = global.weights[layer].map_with_index(|x, row, column| {
+                x + (first.deltas[layer].get(row, column)
+                    + 3.0 * second.deltas[layer].get(row, column))
+                    / 4.0
+            });
+            for (x, y) in weights.row_major_iter().zip(expected.row_major_iter()) {
+                assert!((x - y).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn average_rejects_invalid_updates() {
+        let global = random_network(9);
+        let mut round = FederatedAverage::new(&global);
+        assert!(matches!(round.average(), Err(FederatedError::NoUpdates)));
+        let stale = random_network(10).update_since(&random_network(11), 5);
+        assert!(matches!(
+            round.add_update(&stale),
+            Err(FederatedError::BaseMismatch { .. })
+        ));
+        let empty = random_network(10).update_since(&global, 0);
+        assert!(matches!(
+            round.add_update(&empty),
+            Err(FederatedError::NoSamples)
+        ));
+        assert_eq!(round.updates(), 0);
+    }
+
+    #[test]
+    fn split_shares_images_evenly() {
+        let shards = dataset(10).split(3, 1).unwrap();
+        let sizes: Vec<usize> = shards.iter().map(|shard| shard.images.len()).collect();
+        assert_eq!(sizes, vec![4, 3, 3]);
+        let mut pixels: Vec<f64> = shards
+            .iter()
+            .flat_map(|shard| shard.images.iter().map(|image| image.data[0]))
+            .collect();
+        pixels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pixels, (0..10).map(|i| i as f64).collect::<Vec<f64>>());
+        for shard in shards.iter() {
+            for (image, label) in shard.images.iter().zip(shard.labels.iter()) {
+                assert_eq!(*label, MBTI::from(image.data[0] as usize % 16));
+            }
+        }
+    }
+
+    #[test]
+    fn simulation_rejects_no_clients_or_images() {
+        let mut network = random_network(12);
+        assert!(matches!(
+            network.federated_rounds(&dataset(4), 0, 1, 1, 0),
+            Err(FederatedError::NoClients)
+        ));
+        assert!(matches!(
+            network.federated_rounds(&dataset(0), 2, 1, 1, 0),
+            Err(FederatedError::EmptyDataset)
+        ));
+    }
+
+    #[test]
+    fn simulation_keeps_the_clients_privacy_budget() {
+        let mut network = random_network(13);
+        network.set_differential_privacy(1.0, 1.1, 1e-5);
+        network.federated_rounds(&dataset(12), 3, 2, 1, 0).unwrap();
+        let budget = network
+            .privacy
+            .clone()
+            .expect("Budget should be carried over");
+        let mut client = random_network(13);
+        client.set_differential_privacy(1.0, 1.1, 1e-5);
+        let shard = dataset(4);
+        client.train_without_saving(&shard);
+        client.train_without_saving(&shard);
+        // every shard has 4 images, so each round spends what a client training
+        // on its own shard for an epoch would
+        assert_eq!(Some(budget), client.privacy);
+        assert_eq!(network.epochs, 2);
+    }
+
+    #[test]
+    fn simulation_records_each_round_in_the_history() {
+        let epochs = |network: &NeuralNetwork| -> Vec<i64> {
+            serde_json::to_value(&network.history)
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|record| record["epoch"].as_i64().unwrap())
+                .collect()
+        };
+        let mut network = random_network(14);
+        network.federated_rounds(&dataset(12), 3, 1, 2, 0).unwrap();
+        let after_first_round = network.clone();
+        network.federated_rounds(&dataset(12), 3, 1, 2, 0).unwrap();
+        assert_eq!(network.epochs, 4);
+        assert_eq!(epochs(&network), vec![2, 4]);
+        network.rollback_epochs(1).unwrap();
+        assert_eq!(network.epochs, 2);
+        assert_eq!(network.weights, after_first_round.weights);
+        assert_eq!(epochs(&network), vec![2]);
+    }
+}
@@ -0,0 +1,437 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::JsValue;
+
+use easy_ml::matrices::Matrix;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::hash::sha256_hex;
+use crate::privacy::PrivacyBudget;
+use crate::{
+    now, NeuralNetwork, BATCH_SIZE, FIRST_HIDDEN_LAYER_SIZE, HEIGHT, LEARNING_RATE,
+    LEARNING_RATE_DISCOUNT_FACTOR, MBTI, OUTPUT_LAYER_SIZE, SECOND_HIDDEN_LAYER_SIZE, WIDTH,
+};
+
+/// Identifies JSON files written by `NeuralNetwork::to_json`
+const FORMAT_NAME: &str = "myers-briggs-predictor";
+/// The version of the model file envelope written by this code. Files with no
+/// envelope at all, like `worker/data/network.json`, are treated as version 0
+/// and migrated on load.
+const FORMAT_VERSION: u32 = 1;
+
+/// A self describing envelope around the serialised network, recording what the
+/// weights are for and how they were produced.
+#[derive(Serialize, Deserialize)]
+struct ModelFile<N> {
+    format: String,
+    version: u32,
+    /// ISO 8601 time the file was written
+    created: String,
+    architecture: Architecture,
+    training: Hyperparameters,
+    /// SHA-256 of the network field serialised as compact JSON
+    checksum: String,
+    network: N,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Architecture {
+    input_width: usize,
+    input_height: usize,
+    layers: Vec<Layer>,
+    output: String,
+    /// Names of the MBTIs in the order of the output layer
+    labels: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Layer {
+    inputs: usize,
+    outputs: usize,
+    activation: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Hyperparameters {
+    learning_rate: f64,
+    learning_rate_discount_factor: f64,
+    batch_size: usize,
+    epochs: i32,
+    /// The (ε, δ) spent training with DP-SGD, if any epochs were
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    privacy: Option<PrivacyBudget>,
+}
+
+/// The reasons a model file can be rejected when loading it
+#[derive(Debug)]
+pub enum ModelFileError {
+    Json(serde_json::Error),
+    UnknownFormat(String),
+    UnsupportedVersion(u32),
+    ArchitectureMismatch,
+    LayerCount {
+        expected: usize,
+        actual: usize,
+    },
+    MalformedLayer {
+        layer: usize,
+    },
+    LayerShape {
+        layer: usize,
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    UnknownPrecision(u32),
+    InvalidLength {
+        expected: usize,
+        actual: usize,
+    },
+    MissingTensor(String),
+    UnsupportedDataType(String),
+    InvalidArchive(String),
+}
+
+impl fmt::Display for ModelFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelFileError::Json(error) => write!(f, "Invalid model JSON: {}", error),
+            ModelFileError::UnknownFormat(format) => {
+                write!(f, "Unknown model file format \"{}\"", format)
+            }
+            ModelFileError::UnsupportedVersion(version) => write!(
+                f,
+                "Model file version {} is newer than the supported version {}",
+                version, FORMAT_VERSION
+            ),
+            ModelFileError::ArchitectureMismatch => {
+                write!(f, "Model architecture does not match this network")
+            }
+            ModelFileError::LayerCount { expected, actual } => write!(
+                f,
+                "Expected {} weight matrices but found {}",
+                expected, actual
+            ),
+            ModelFileError::MalformedLayer { layer } => write!(
+                f,
+                "Weight matrix {} does not have rows * columns values",
+                layer
+            ),
+            ModelFileError::LayerShape {
+                layer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Weight matrix {} should be {}x{} but is {}x{}",
+                layer, expected.0, expected.1, actual.0, actual.1
+            ),
+            ModelFileError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Model checksum {} does not match its contents {}",
+                expected, actual
+            ),
+            ModelFileError::UnknownPrecision(bytes) => {
+                write!(f, "Unknown {} byte weight precision", bytes)
+            }
+            ModelFileError::InvalidLength { expected, actual } => write!(
+                f,
+                "Expected {} bytes of model data but found {}",
+                expected, actual
+            ),
+            ModelFileError::MissingTensor(name) => write!(f, "No tensor named {} found", name),
+            ModelFileError::UnsupportedDataType(data_type) => {
+                write!(f, "Unsupported tensor data type {}", data_type)
+            }
+            ModelFileError::InvalidArchive(reason) => write!(f, "Invalid archive: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ModelFileError {}
+
+impl From<serde_json::Error> for ModelFileError {
+    fn from(error: serde_json::Error) -> Self {
+        ModelFileError::Json(error)
+    }
+}
+
+impl From<ModelFileError> for JsValue {
+    fn from(error: ModelFileError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+/// The names each weight matrix is stored under when exported as named tensors
+pub(crate) const LAYER_NAMES: [&str; 3] =
+    ["first_hidden_layer", "second_hidden_layer", "output_layer"];
+
+/// An n dimensional array of values read from a file, in row major order
+pub(crate) struct Tensor {
+    pub(crate) shape: Vec<usize>,
+    pub(crate) data: Vec<f64>,
+}
+
+/// The (rows, columns) of each weight matrix of the network
+pub(crate) fn layer_shapes() -> [(usize, usize); 3] {
+    [
+        (WIDTH * HEIGHT, FIRST_HIDDEN_LAYER_SIZE),
+        (FIRST_HIDDEN_LAYER_SIZE, SECOND_HIDDEN_LAYER_SIZE),
+        (SECOND_HIDDEN_LAYER_SIZE, OUTPUT_LAYER_SIZE),
+    ]
+}
+
+impl Architecture {
+    fn current() -> Architecture {
+        let activations = ["sigmoid", "sigmoid", "linear"];
+        Architecture {
+            input_width: WIDTH,
+            input_height: HEIGHT,
+            layers: layer_shapes()
+                .iter()
+                .zip(activations.iter())
+                .map(|(&(inputs, outputs), activation)| Layer {
+                    inputs,
+                    outputs,
+                    activation: activation.to_string(),
+                })
+                .collect(),
+            output: "softmax".to_string(),
+            labels: (0..OUTPUT_LAYER_SIZE)
+                .map(|i| <&str>::from(MBTI::from(i)).to_string())
+                .collect(),
+        }
+    }
+}
+
+impl NeuralNetwork {
+    /// The SHA-256 of the network serialised as compact JSON, which identifies
+    /// the exact weights and training state.
+    pub(crate) fn checksum(&self) -> String {
+        sha256_hex(
+            serde_json::to_string(self)
+                .expect("Failed to serialise neural network")
+                .as_bytes(),
+        )
+    }
+}
+
+/// Serialises the network inside a versioned envelope
+pub(crate) fn to_json(network: &NeuralNetwork) -> String {
+    let file = ModelFile {
+        format: FORMAT_NAME.to_string(),
+        version: FORMAT_VERSION,
+        created: iso_timestamp(now()),
+        architecture: Architecture::current(),
+        training: Hyperparameters {
+            learning_rate: LEARNING_RATE,
+            learning_rate_discount_factor: LEARNING_RATE_DISCOUNT_FACTOR,
+            batch_size: BATCH_SIZE,
+            epochs: network.epochs,
+            privacy: network.privacy.clone(),
+        },
+        checksum: network.checksum(),
+        network,
+    };
+    serde_json::to_string(&file).expect("Failed to serialise neural network")
+}
+
+/// Deserialises a network from either a versioned model file or the bare network
+/// JSON written before model files had an envelope, checking that every weight
+/// matrix has the shape this network needs.
+pub(crate) fn from_json(json: &str) -> Result<NeuralNetwork, ModelFileError> {
+    let value: Value = serde_json::from_str(json)?;
+    if value.get("format").is_none() {
+        // version 0 files are just the network, so only the shapes can be checked
+        validate_weights(value.get("weights"))?;
+        return Ok(serde_json::from_value(value)?);
+    }
+    let file: ModelFile<Value> = serde_json::from_value(value)?;
+    if file.format != FORMAT_NAME {
+        return Err(ModelFileError::UnknownFormat(file.format));
+    }
+    if file.version > FORMAT_VERSION {
+        return Err(ModelFileError::UnsupportedVersion(file.version));
+    }
+    if file.architecture != Architecture::current() {
+        return Err(ModelFileError::ArchitectureMismatch);
+    }
+    validate_weights(file.network.get("weights"))?;
+    let network: NeuralNetwork = serde_json::from_value(file.network)?;
+    let checksum = network.checksum();
+    if checksum != file.checksum {
+        return Err(ModelFileError::ChecksumMismatch {
+            expected: file.checksum,
+            actual: checksum,
+        });
+    }
+    Ok(network)
+}
+
+/// Checks serialised matrices, one for each layer, have the right shapes and
+/// amount of data before they are turned into Matrices, which would otherwise
+/// accept them and panic when used.
+pub(crate) fn validate_weights(weights: Option<&Value>) -> Result<(), ModelFileError> {
+    let weights = weights
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
+    check_layer_count(weights.len())?;
+    for (layer, matrix) in weights.iter().enumerate() {
+        let dimension = |name| matrix.get(name).and_then(Value::as_u64).map(|x| x as usize);
+        let length = matrix.get("data").and_then(Value::as_array).map(Vec::len);
+        let (rows, columns, length) = match (dimension("rows"), dimension("columns"), length) {
+            (Some(rows), Some(columns), Some(length)) => (rows, columns, length),
+            _ => return Err(ModelFileError::MalformedLayer { layer }),
+        };
+        check_layer_shape(layer, (rows, columns))?;
+        if rows * columns != length {
+            return Err(ModelFileError::MalformedLayer { layer });
+        }
+    }
+    Ok(())
+}
+
+/// Checks a model has one weight matrix for each layer of the network
+pub(crate) fn check_layer_count(layers: usize) -> Result<(), ModelFileError> {
+    if layers != layer_shapes().len() {
+        return Err(ModelFileError::LayerCount {
+            expected: layer_shapes().len(),
+            actual: layers,
+        });
+    }
+    Ok(())
+}
+
+/// Checks the (rows, columns) of a weight matrix match its layer of the network
+pub(crate) fn check_layer_shape(layer: usize, shape: (usize, usize)) -> Result<(), ModelFileError> {
+    let expected = layer_shapes()[layer];
+    if shape != expected {
+        return Err(ModelFileError::LayerShape {
+            layer,
+            expected,
+            actual: shape,
+        });
+    }
+    Ok(())
+}
+
+/// Creates a network from the tensor of each layer in `LAYER_NAMES`, checking every
+/// one is a matrix with the shape of its layer.
+pub(crate) fn network_from_tensors(
+    tensors: &mut HashMap<String, Tensor>,
+    epochs: i32,
+    temperature: f64,
+) -> Result<NeuralNetwork, ModelFileError> {
+    let mut weights = Vec::with_capacity(LAYER_NAMES.len());
+    for (layer, name) in LAYER_NAMES.iter().enumerate() {
+        let tensor = tensors
+            .remove(*name)
+            .ok_or_else(|| ModelFileError::MissingTensor(name.to_string()))?;
+        let shape = match tensor.shape[..] {
+            [rows, columns] => (rows, columns),
+            _ => return Err(ModelFileError::MalformedLayer { layer }),
+        };
+        check_layer_shape(layer, shape)?;
+        if tensor.data.len() != shape.0 * shape.1 {
+            return Err(ModelFileError::MalformedLayer { layer });
+        }
+        weights.push(Matrix::from_flat_row_major(shape, tensor.data));
+    }
+    let mut network = NeuralNetwork::from_weights(weights);
+    network.epochs = epochs;
+    network.temperature = temperature;
+    Ok(network)
+}
+
+/// Formats milliseconds since the Unix epoch as an ISO 8601 UTC timestamp
+pub(crate) fn iso_timestamp(millis: f64) -> String {
+    let millis = millis.max(0.0) as u64;
+    let seconds = millis / 1000;
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    // convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        (time % 3600) / 60,
+        time % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::SeededRandomGenerator;
+
+    /// A network with weights drawn at full precision, like a trained network's,
+    /// rather than short decimals which any float parser reads back exactly.
+    pub(crate) fn random_network(seed: u64) -> NeuralNetwork {
+        let mut random = SeededRandomGenerator::new(seed);
+        NeuralNetwork::from_weights(
+            layer_shapes()
+                .iter()
+                .map(|&(rows, columns)| {
+                    Matrix::from_flat_row_major(
+                        (rows, columns),
+                        (0..rows * columns).map(|_| random.normal() * 0.7).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn full_precision_weights_round_trip() {
+        for seed in 0..4 {
+            let mut network = random_network(seed);
+            network.epochs = 3;
+            network.temperature = 1.0 / 3.0;
+            let loaded = from_json(&to_json(&network)).unwrap();
+            assert_eq!(loaded.weights, network.weights);
+            assert_eq!(loaded.temperature.to_bits(), network.temperature.to_bits());
+            assert_eq!(loaded.checksum(), network.checksum());
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_weights() {
+        let network = random_network(9);
+        let json = to_json(&network);
+        let first = network.weights[0].get(0, 0);
+        let tampered = json.replacen(
+            &serde_json::to_string(&first).unwrap(),
+            &serde_json::to_string(&(first + 1.0)).unwrap(),
+            1,
+        );
+        assert!(matches!(
+            from_json(&tampered),
+            Err(ModelFileError::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use crate::checkpoint::CheckpointMetric;
+use crate::NeuralNetwork;
+
+/// What happened in one epoch of training
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EpochRecord {
+    /// The number of epochs the network had been trained for after this one
+    epoch: i32,
+    /// Average clean loss over the epoch, which is NaN if training diverged
+    #[serde(deserialize_with = "nan_if_null")]
+    loss: f64,
+    /// Average loss on adversarial copies, if adversarial training was enabled
+    adversarial_loss: Option<f64>,
+    learning_rate: f64,
+    /// Milliseconds the epoch took to train
+    wall_time: f64,
+    /// The clean loss of each batch, in the order they were trained on
+    #[serde(deserialize_with = "nans_if_null")]
+    batch_losses: Vec<f64>,
+    /// Set by `record_accuracy` once the epoch has been evaluated
+    training_accuracy: Option<f64>,
+    test_accuracy: Option<f64>,
+}
+
+/// Every epoch the network has been trained for since it started recording them,
+/// which is saved with the network so its learning curve survives a reload.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TrainingHistory {
+    epochs: Vec<EpochRecord>,
+}
+
+/// JSON has no NaN, so serde_json writes non finite floats as null, which are
+/// read back as NaN here rather than rejecting the file.
+fn nan_if_null<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(f64::NAN))
+}
+
+fn nans_if_null<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f64>, D::Error> {
+    Ok(Vec::<Option<f64>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|x| x.unwrap_or(f64::NAN))
+        .collect())
+}
+
+impl TrainingHistory {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.epochs.is_empty()
+    }
+
+    /// Forgets every epoch after the first `epochs`
+    pub(crate) fn truncate(&mut self, epochs: i32) {
+        self.epochs.retain(|record| record.epoch <= epochs);
+    }
+
+    pub(crate) fn record_epoch(
+        &mut self,
+        epoch: i32,
+        loss: f64,
+        adversarial_loss: Option<f64>,
+        learning_rate: f64,
+        wall_time: f64,
+        batch_losses: Vec<f64>,
+    ) {
+        self.epochs.push(EpochRecord {
+            epoch,
+            loss,
+            adversarial_loss,
+            learning_rate,
+            wall_time,
+            batch_losses,
+            training_accuracy: None,
+            test_accuracy: None,
+        });
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Returns the record of every epoch trained, with its loss, learning rate,
+    /// wall time, batch loss curve and any accuracies recorded for it.
+    pub fn training_history(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.history).expect("Failed to serialise training history")
+    }
+
+    /// Records the accuracies of the most recently trained epoch, so they are kept
+    /// in its history, and the best checkpoint can be judged by them. Nothing is
+    /// added to the history if no epochs have been recorded.
+    pub fn record_accuracy(&mut self, training_accuracy: Option<f64>, test_accuracy: Option<f64>) {
+        if let Some(record) = self.history.epochs.last_mut() {
+            record.training_accuracy = training_accuracy;
+            record.test_accuracy = test_accuracy;
+        }
+        if let Some(accuracy) = training_accuracy {
+            self.measure_checkpoint(CheckpointMetric::TrainingAccuracy, accuracy);
+        }
+        if let Some(accuracy) = test_accuracy {
+            self.measure_checkpoint(CheckpointMetric::TestAccuracy, accuracy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+
+    /// A network which has recorded `epochs` epochs, the second of which diverged
+    fn trained_network(epochs: i32) -> NeuralNetwork {
+        let mut network = random_network(0);
+        for epoch in 1..=epochs {
+            let loss = if epoch == 2 {
+                f64::NAN
+            } else {
+                1.0 / epoch as f64
+            };
+            network.epochs = epoch;
+            network.history.record_epoch(
+                epoch,
+                loss,
+                Some(loss * 2.0),
+                0.1,
+                12.5,
+                vec![loss, f64::NAN],
+            );
+        }
+        network
+    }
+
+    #[test]
+    fn records_epochs_in_order() {
+        let history = trained_network(3).history;
+        let epochs: Vec<i32> = history.epochs.iter().map(|record| record.epoch).collect();
+        assert_eq!(epochs, vec![1, 2, 3]);
+        let record = &history.epochs[2];
+        assert_eq!(record.loss, 1.0 / 3.0);
+        assert_eq!(record.adversarial_loss, Some(2.0 / 3.0));
+        assert_eq!(record.training_accuracy, None);
+        assert_eq!(record.test_accuracy, None);
+    }
+
+    #[test]
+    fn records_accuracy_for_the_latest_epoch() {
+        let mut network = random_network(1);
+        // nothing to record against yet
+        network.record_accuracy(Some(0.5), None);
+        assert!(network.history.is_empty());
+
+        let mut network = trained_network(2);
+        network.record_accuracy(Some(0.75), Some(0.5));
+        let records = &network.history.epochs;
+        assert_eq!(records[0].training_accuracy, None);
+        assert_eq!(records[1].training_accuracy, Some(0.75));
+        assert_eq!(records[1].test_accuracy, Some(0.5));
+    }
+
+    #[test]
+    fn truncate_forgets_later_epochs() {
+        let mut history = trained_network(4).history;
+        history.truncate(2);
+        let epochs: Vec<i32> = history.epochs.iter().map(|record| record.epoch).collect();
+        assert_eq!(epochs, vec![1, 2]);
+        history.truncate(0);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn round_trips_through_json_with_nan_losses() {
+        let mut network = trained_network(3);
+        network.record_accuracy(Some(1.0 / 3.0), Some(2.0 / 3.0));
+        let loaded = crate::format::from_json(&network.to_json()).unwrap();
+        assert_eq!(loaded.history.epochs.len(), 3);
+        for (original, loaded) in network
+            .history
+            .epochs
+            .iter()
+            .zip(loaded.history.epochs.iter())
+        {
+            assert_eq!(loaded.epoch, original.epoch);
+            assert_eq!(loaded.loss.to_bits(), original.loss.to_bits());
+            assert_eq!(loaded.training_accuracy, original.training_accuracy);
+            assert_eq!(loaded.test_accuracy, original.test_accuracy);
+            assert_eq!(
+                loaded.batch_losses[0].to_bits(),
+                original.batch_losses[0].to_bits()
+            );
+            assert!(loaded.batch_losses[1].is_nan());
+        }
+        assert!(loaded.history.epochs[1].loss.is_nan());
+        // a NaN adversarial loss is written as null and so read back as missing
+        assert_eq!(loaded.history.epochs[1].adversarial_loss, None);
+        assert_eq!(loaded.checksum(), network.checksum());
+    }
+}
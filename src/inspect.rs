@@ -0,0 +1,122 @@
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use crate::{Dataset, Image, NeuralNetwork};
+
+/// Sigmoid outputs closer than this to 0 or 1 are counted as saturated, as the
+/// gradient through them is almost nothing.
+const SATURATION_THRESHOLD: f64 = 0.01;
+
+/// The outputs of every layer of the network for a single image
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct Activations {
+    first_hidden_layer: Vec<f64>,
+    second_hidden_layer: Vec<f64>,
+    logits: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl Activations {
+    /// The 128 sigmoid outputs of the first hidden layer
+    pub fn first_hidden_layer(&self) -> Vec<f64> {
+        self.first_hidden_layer.clone()
+    }
+
+    /// The 64 sigmoid outputs of the second hidden layer
+    pub fn second_hidden_layer(&self) -> Vec<f64> {
+        self.second_hidden_layer.clone()
+    }
+
+    /// The 16 outputs of the network before softmax is applied, indexed by the usize
+    /// conversion of the MBTI
+    pub fn logits(&self) -> Vec<f64> {
+        self.logits.clone()
+    }
+}
+
+/// Summary statistics for the units of one hidden layer over a dataset
+#[derive(Clone, Debug, Serialize)]
+pub struct LayerStatistics {
+    units: usize,
+    mean: f64,
+    standard_deviation: f64,
+    /// Fraction of all unit outputs over the dataset which were saturated
+    saturated_fraction: f64,
+    /// Fraction of units which were saturated for every image in the dataset, and
+    /// so can no longer learn or tell any two images apart
+    dead_fraction: f64,
+    /// Mean output of each unit over the dataset
+    unit_means: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Returns the output of each layer of the network for an image, as computed
+    /// by `classify`.
+    pub fn activations(&self, image: &Image) -> Activations {
+        let (layer1, layer2, output) = self.feedforward(image);
+        Activations {
+            first_hidden_layer: layer1.row_major_iter().collect(),
+            second_hidden_layer: layer2.row_major_iter().collect(),
+            logits: output.row_major_iter().collect(),
+        }
+    }
+
+    /// Returns a list of statistics for the first and second hidden layers over
+    /// every image in the dataset.
+    pub fn activation_statistics(&self, dataset: &Dataset) -> JsValue {
+        let mut first_hidden_layer = Vec::with_capacity(dataset.images.len());
+        let mut second_hidden_layer = Vec::with_capacity(dataset.images.len());
+        for image in dataset.images.iter() {
+            let (layer1, layer2, _) = self.feedforward(image);
+            first_hidden_layer.push(layer1.row_major_iter().collect());
+            second_hidden_layer.push(layer2.row_major_iter().collect());
+        }
+        let statistics = vec![
+            LayerStatistics::new(&first_hidden_layer),
+            LayerStatistics::new(&second_hidden_layer),
+        ];
+        serde_wasm_bindgen::to_value(&statistics).expect("Failed to serialise statistics")
+    }
+}
+
+impl LayerStatistics {
+    /// Computes the statistics of a layer from its outputs on each image
+    fn new(outputs: &[Vec<f64>]) -> LayerStatistics {
+        let units = outputs.first().map(|output| output.len()).unwrap_or(0);
+        let samples = outputs.len() as f64;
+        let total = samples * units as f64;
+        let saturated = |x: f64| !(SATURATION_THRESHOLD..=1.0 - SATURATION_THRESHOLD).contains(&x);
+        let mut unit_means = vec![0.0; units];
+        let mut always_saturated = vec![true; units];
+        let mut saturated_count = 0;
+        for output in outputs {
+            for (unit, &x) in output.iter().enumerate() {
+                unit_means[unit] += x / samples;
+                if saturated(x) {
+                    saturated_count += 1;
+                } else {
+                    always_saturated[unit] = false;
+                }
+            }
+        }
+        let mean = unit_means.iter().sum::<f64>() / units as f64;
+        let variance = outputs
+            .iter()
+            .flat_map(|output| output.iter())
+            .map(|x| (x - mean) * (x - mean))
+            .sum::<f64>()
+            / total;
+        let dead = always_saturated.iter().filter(|&&dead| dead).count();
+        LayerStatistics {
+            units,
+            mean,
+            standard_deviation: variance.sqrt(),
+            saturated_fraction: saturated_count as f64 / total,
+            dead_fraction: dead as f64 / units as f64,
+            unit_means,
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! An append only record of every version of a network. Each entry is chained to
+//! the one before it by hash, so editing, removing or reordering past entries is
+//! detected by `Ledger::verify`, and the weights of every version are kept in a
+//! `ModelStore` so any of them can be checked out again.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::format::iso_timestamp;
+use crate::hash::sha256_hex;
+use crate::store::{FilesystemStore, MemoryStore, ModelStore, StoreError};
+use crate::{now, NeuralNetwork};
+
+/// The file in a ledger directory holding one JSON entry per line
+const LEDGER_FILE: &str = "ledger.jsonl";
+
+/// One version of a network in the ledger
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Position in the ledger, starting from 0
+    pub index: u64,
+    /// The `content_hash` of the network
+    pub model_hash: String,
+    /// The `content_hash` of the network in the previous entry
+    pub parent_hash: Option<String>,
+    pub epochs: i32,
+    /// Named measurements of the network, such as accuracy on the test dataset
+    pub metrics: BTreeMap<String, f64>,
+    /// ISO 8601 time the entry was appended
+    pub timestamp: String,
+    /// The hash of the previous entry
+    pub previous: Option<String>,
+    /// The SHA-256 of every other field of this entry
+    pub hash: String,
+}
+
+/// The fields of an entry which its hash covers
+#[derive(Serialize)]
+struct EntryContents<'a> {
+    index: u64,
+    model_hash: &'a str,
+    parent_hash: &'a Option<String>,
+    epochs: i32,
+    /// The bits of each metric, so the hash doesn't depend on how the decimal
+    /// text of a float is written and read back
+    metrics: BTreeMap<&'a str, u64>,
+    timestamp: &'a str,
+    previous: &'a Option<String>,
+}
+
+impl LedgerEntry {
+    fn compute_hash(&self) -> String {
+        let contents = EntryContents {
+            index: self.index,
+            model_hash: &self.model_hash,
+            parent_hash: &self.parent_hash,
+            epochs: self.epochs,
+            metrics: self
+                .metrics
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.to_bits()))
+                .collect(),
+            timestamp: &self.timestamp,
+            previous: &self.previous,
+        };
+        sha256_hex(
+            serde_json::to_string(&contents)
+                .expect("Failed to serialise ledger entry")
+                .as_bytes(),
+        )
+    }
+}
+
+/// The reasons appending to, verifying or checking out from a ledger can fail
+#[derive(Debug)]
+pub enum LedgerError {
+    /// The entry at an index does not follow from the entries before it
+    Tampered {
+        index: u64,
+        reason: String,
+    },
+    /// No entry records a network with this hash
+    NotFound(String),
+    /// Metrics must be finite to be stored as JSON
+    InvalidMetric(String),
+    Store(StoreError),
+    Json(serde_json::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::Tampered { index, reason } => {
+                write!(
+                    f,
+                    "Ledger entry {} has been tampered with: {}",
+                    index, reason
+                )
+            }
+            LedgerError::NotFound(hash) => write!(f, "No ledger entry for model {}", hash),
+            LedgerError::InvalidMetric(name) => write!(f, "Metric {} is not finite", name),
+            LedgerError::Store(error) => write!(f, "{}", error),
+            LedgerError::Json(error) => write!(f, "Invalid ledger entry: {}", error),
+            LedgerError::Io(error) => write!(f, "Ledger failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+impl From<StoreError> for LedgerError {
+    fn from(error: StoreError) -> Self {
+        LedgerError::Store(error)
+    }
+}
+
+impl From<serde_json::Error> for LedgerError {
+    fn from(error: serde_json::Error) -> Self {
+        LedgerError::Json(error)
+    }
+}
+
+impl From<io::Error> for LedgerError {
+    fn from(error: io::Error) -> Self {
+        LedgerError::Io(error)
+    }
+}
+
+/// A hash chained list of network versions, with the weights of each version
+/// kept in a store. This is a local stand in for immutable shared storage.
+#[derive(Debug)]
+pub struct Ledger<S: ModelStore> {
+    store: S,
+    entries: Vec<LedgerEntry>,
+    /// Where entries are appended on disk, if the ledger is not only in memory
+    file: Option<PathBuf>,
+}
+
+impl Ledger<MemoryStore> {
+    /// Creates an empty ledger which only exists in memory
+    pub fn in_memory() -> Ledger<MemoryStore> {
+        Ledger::new(MemoryStore::new())
+    }
+}
+
+impl Ledger<FilesystemStore> {
+    /// Opens the ledger in a directory, creating it if it doesn't exist. Entries
+    /// are appended to `ledger.jsonl` and weights kept in a `FilesystemStore` in
+    /// the same directory. The entries read back are not verified until `verify`
+    /// is called.
+    pub fn open<P: Into<PathBuf>>(directory: P) -> Result<Ledger<FilesystemStore>, LedgerError> {
+        let directory = directory.into();
+        let store = FilesystemStore::new(&directory)?;
+        let file = directory.join(LEDGER_FILE);
+        let entries = match fs::read_to_string(&file) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<LedgerEntry>, _>>()?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+        Ok(Ledger {
+            store,
+            entries,
+            file: Some(file),
+        })
+    }
+}
+
+impl<S: ModelStore> Ledger<S> {
+    /// Creates an empty in memory ledger which keeps weights in a store
+    pub fn new(store: S) -> Ledger<S> {
+        Ledger {
+            store,
+            entries: Vec::new(),
+            file: None,
+        }
+    }
+
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    /// The most recently appended entry
+    pub fn head(&self) -> Option<&LedgerEntry> {
+        self.entries.last()
+    }
+
+    /// Records a snapshot of the network as the next version in the ledger, with
+    /// the previous version as its parent, and saves its weights to the store.
+    pub async fn append(
+        &mut self,
+        network: &NeuralNetwork,
+        metrics: BTreeMap<String, f64>,
+    ) -> Result<&LedgerEntry, LedgerError> {
+        if let Some((name, _)) = metrics.iter().find(|(_, value)| !value.is_finite()) {
+            return Err(LedgerError::InvalidMetric(name.clone()));
+        }
+        let head = self.head();
+        let parent_hash = head.map(|entry| entry.model_hash.clone());
+        let model_hash = network.save_to(&self.store, parent_hash.as_deref()).await?;
+        let mut entry = LedgerEntry {
+            index: self.entries.len() as u64,
+            model_hash,
+            parent_hash,
+            epochs: network.epochs,
+            metrics,
+            timestamp: iso_timestamp(now()),
+            previous: head.map(|entry| entry.hash.clone()),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        if let Some(file) = &self.file {
+            let mut file = OpenOptions::new().create(true).append(true).open(file)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        self.entries.push(entry);
+        Ok(self.entries.last().unwrap())
+    }
+
+    /// Checks every entry's hash matches its contents and chains to the entry
+    /// before it, returning the first entry which doesn't.
+    pub fn verify(&self) -> Result<(), LedgerError> {
+        let mut previous: Option<&LedgerEntry> = None;
+        for (index, entry) in self.entries.iter().enumerate() {
+            let tampered = |reason: &str| LedgerError::Tampered {
+                index: index as u64,
+                reason: reason.to_string(),
+            };
+            if entry.index != index as u64 {
+                return Err(tampered("the entry is out of order"));
+            }
+            if entry.hash != entry.compute_hash() {
+                return Err(tampered("the entry does not match its hash"));
+            }
+            if entry.previous != previous.map(|entry| entry.hash.clone()) {
+                return Err(tampered("the entry is not chained to the one before it"));
+            }
+            if entry.parent_hash != previous.map(|entry| entry.model_hash.clone()) {
+                return Err(tampered("the parent is not the model before it"));
+            }
+            previous = Some(entry);
+        }
+        Ok(())
+    }
+
+    /// Loads the version of the network recorded with a content hash, after
+    /// verifying the ledger and that the stored weights have that hash.
+    pub async fn checkout(&self, model_hash: &str) -> Result<NeuralNetwork, LedgerError> {
+        self.verify()?;
+        if !self
+            .entries
+            .iter()
+            .any(|entry| entry.model_hash == model_hash)
+        {
+            return Err(LedgerError::NotFound(model_hash.to_string()));
+        }
+        Ok(NeuralNetwork::load_from(&self.store, model_hash).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::format::tests::random_network;
+
+    fn metrics(test_accuracy: f64) -> BTreeMap<String, f64> {
+        let mut metrics = BTreeMap::new();
+        metrics.insert("test_accuracy".to_string(), test_accuracy);
+        metrics
+    }
+
+    /// Appends three versions of a network with full precision metrics
+    fn append_versions<S: ModelStore>(ledger: &mut Ledger<S>) -> Vec<NeuralNetwork> {
+        (0..3)
+            .map(|version| {
+                let mut network = random_network(version);
+                network.epochs = version as i32;
+                let accuracy = 1.0 / (version as f64 + 3.0);
+                block_on(ledger.append(&network, metrics(accuracy))).unwrap();
+                network
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_chained_entries() {
+        let mut ledger = Ledger::in_memory();
+        let networks = append_versions(&mut ledger);
+        ledger.verify().unwrap();
+        let entries = ledger.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].previous, None);
+        assert_eq!(entries[0].parent_hash, None);
+        for (entry, network) in entries.iter().zip(networks.iter()) {
+            assert_eq!(entry.model_hash, network.content_hash());
+        }
+        for pair in entries.windows(2) {
+            assert_eq!(pair[1].previous.as_ref(), Some(&pair[0].hash));
+            assert_eq!(pair[1].parent_hash.as_ref(), Some(&pair[0].model_hash));
+        }
+        let checked_out = block_on(ledger.checkout(&entries[1].model_hash)).unwrap();
+        assert_eq!(checked_out.weights, networks[1].weights);
+        assert!(matches!(
+            block_on(ledger.checkout("missing")),
+            Err(LedgerError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_metrics_which_are_not_finite() {
+        let mut ledger = Ledger::in_memory();
+        assert!(matches!(
+            block_on(ledger.append(&random_network(0), metrics(f64::NAN))),
+            Err(LedgerError::InvalidMetric(_))
+        ));
+        assert!(ledger.entries().is_empty());
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut ledger = Ledger::in_memory();
+        append_versions(&mut ledger);
+        let tampered_at = |edit: fn(&mut Vec<LedgerEntry>)| {
+            let mut entries = ledger.entries.clone();
+            edit(&mut entries);
+            let tampered = Ledger {
+                store: MemoryStore::new(),
+                entries,
+                file: None,
+            };
+            match tampered.verify() {
+                Err(LedgerError::Tampered { index, .. }) => Some(index),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            tampered_at(|entries| {
+                entries[1].metrics.insert("test_accuracy".to_string(), 0.99);
+            }),
+            Some(1)
+        );
+        assert_eq!(tampered_at(|entries| entries[2].epochs += 1), Some(2));
+        assert_eq!(tampered_at(|entries| entries.swap(1, 2)), Some(1));
+        assert_eq!(
+            tampered_at(|entries| {
+                entries.remove(1);
+            }),
+            Some(1)
+        );
+        assert_eq!(
+            tampered_at(|entries| {
+                // rehashing an edited entry breaks the chain to the next one
+                entries[0].epochs = 10;
+                entries[0].hash = entries[0].compute_hash();
+            }),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn reopened_ledger_verifies_and_checks_out() {
+        let directory = std::env::temp_dir().join(format!("mbti-ledger-{}", std::process::id()));
+        let mut ledger = Ledger::open(&directory).unwrap();
+        let networks = append_versions(&mut ledger);
+        let reopened = Ledger::open(&directory).unwrap();
+        assert_eq!(reopened.entries(), ledger.entries());
+        reopened.verify().unwrap();
+        for (entry, network) in reopened.entries().iter().zip(networks.iter()) {
+            let checked_out = block_on(reopened.checkout(&entry.model_hash)).unwrap();
+            assert_eq!(checked_out.weights, network.weights);
+        }
+
+        // editing a metric in the file is caught when the ledger is reopened
+        let file = directory.join(LEDGER_FILE);
+        let contents = fs::read_to_string(&file).unwrap();
+        fs::write(
+            &file,
+            contents.replacen("\"test_accuracy\":0.", "\"test_accuracy\":0.9", 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            Ledger::open(&directory).unwrap().verify(),
+            Err(LedgerError::Tampered { index: 0, .. })
+        ));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -8,10 +8,41 @@ use easy_ml::matrices::Matrix;
 use easy_ml::numeric::extra::Real;
 use easy_ml::numeric::Numeric;
 
+use std::borrow::Cow;
 use std::cmp;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 
+use augment::Augmentation;
+use checkpoint::{CheckpointMetric, Checkpoints};
+use history::TrainingHistory;
+use mixing::{Mixing, MixingMethod};
+use privacy::{PrivacyBudget, PrivateTraining};
+use storage::WeightSource;
+
+mod adversarial;
+mod augment;
+mod binary;
+mod calibration;
+mod checkpoint;
+mod embedding;
+mod explain;
+mod federated;
+mod format;
+mod hash;
+mod history;
+mod inspect;
+pub mod ledger;
+mod mixing;
+mod npz;
+mod onnx;
+mod privacy;
+mod safetensors;
+pub mod secure_aggregation;
+mod storage;
+pub mod store;
+mod visualize;
+
 // This is like the `main` function, except for JavaScript.
 #[wasm_bindgen(start)]
 pub fn main_js() -> Result<(), JsValue> {
@@ -49,14 +80,23 @@ extern "C" {
 /**
  * Wraps the JavaScript function in a snake_case name
  */
+#[cfg(target_arch = "wasm32")]
 fn log_progress(percent: f64) {
     logProgress(percent);
 }
 
+#[cfg(target_arch = "wasm32")]
 fn log_batch_loss(percent: f64) {
     logBatchLoss(percent);
 }
 
+/// There is no page to report progress to outside of the browser, such as in tests
+#[cfg(not(target_arch = "wasm32"))]
+fn log_progress(_percent: f64) {}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn log_batch_loss(_percent: f64) {}
+
 fn get_network_weights() -> String {
     return getNetworkWeights();
 }
@@ -99,6 +139,11 @@ impl Image {
             self.data.set_len(WIDTH * HEIGHT);
         }
     }
+
+    /// Copies the pixels of this Image out for JavaScript to draw
+    pub fn pixels(&self) -> Vec<Pixel> {
+        self.data.clone()
+    }
 }
 
 impl From<Image> for Matrix<f64> {
@@ -292,6 +337,31 @@ impl Dataset {
         self.images.push(image);
         self.labels.push(label.try_into().expect("Label invalid"));
     }
+
+    /// Counts how many images of each MBTI are in the dataset, indexed by the
+    /// usize conversion of the MBTI
+    pub fn class_counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; OUTPUT_LAYER_SIZE];
+        for &label in self.labels.iter() {
+            counts[usize::from(label)] += 1;
+        }
+        counts
+    }
+
+    /// Computes per class loss weights which are inversely proportional to how often
+    /// each MBTI occurs in the dataset, scaled so they average 1 over the MBTIs which
+    /// occur, which gives an evenly balanced dataset a weight of 1 for every class.
+    /// Classes with no images get a weight of 0.
+    pub fn inverse_frequency_weights(&self) -> Vec<f64> {
+        let counts = self.class_counts();
+        let inverses: Vec<f64> = counts
+            .iter()
+            .map(|&count| if count == 0 { 0.0 } else { 1.0 / count as f64 })
+            .collect();
+        let present = counts.iter().filter(|&&count| count > 0).count();
+        let mean = inverses.iter().sum::<f64>() / present as f64;
+        inverses.iter().map(|inverse| inverse / mean).collect()
+    }
 }
 
 /// A neural network configuration to classify the mbti data
@@ -301,6 +371,105 @@ pub struct NeuralNetwork {
     weights: Vec<Matrix<f64>>,
     epochs: i32,
     //buffer: Vec<f64>,
+    /// Softmax temperature fitted by calibration, networks which have never been
+    /// calibrated use a temperature of 1.
+    #[serde(default = "calibration::default_temperature")]
+    temperature: f64,
+    /// The privacy spent by the epochs trained with DP-SGD, if there were any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    privacy: Option<PrivacyBudget>,
+    /// The loss, learning rate and timing of every epoch trained
+    #[serde(default, skip_serializing_if = "TrainingHistory::is_empty")]
+    history: TrainingHistory,
+    #[serde(skip)]
+    options: TrainingOptions,
+    /// Average loss on the adversarial copies of images in the last trained epoch
+    #[serde(skip)]
+    last_adversarial_loss: Option<f64>,
+    /// Copies of the network from recent epochs and the best epoch, to roll back to
+    #[serde(skip)]
+    checkpoints: Checkpoints,
+}
+
+/// The reasons a list of class weights can't be used to weight the loss
+#[derive(Debug)]
+enum ClassWeightError {
+    WrongCount(usize),
+    /// Weights must be finite and at least 0, or they flip or poison the loss
+    Invalid {
+        class: usize,
+        weight: f64,
+    },
+}
+
+impl std::fmt::Display for ClassWeightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClassWeightError::WrongCount(count) => write!(
+                f,
+                "A weight must be given for every MBTI, but {} were given",
+                count
+            ),
+            ClassWeightError::Invalid { class, weight } => write!(
+                f,
+                "The weight of {} must be 0 or more, but was {}",
+                <&str>::from(MBTI::from(*class)),
+                weight
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClassWeightError {}
+
+impl From<ClassWeightError> for JsValue {
+    fn from(error: ClassWeightError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+fn check_class_weights(class_weights: &[f64]) -> Result<(), ClassWeightError> {
+    if class_weights.len() != OUTPUT_LAYER_SIZE {
+        return Err(ClassWeightError::WrongCount(class_weights.len()));
+    }
+    match class_weights
+        .iter()
+        .enumerate()
+        .find(|(_, weight)| !(weight.is_finite() && **weight >= 0.0))
+    {
+        Some((class, &weight)) => Err(ClassWeightError::Invalid { class, weight }),
+        None => Ok(()),
+    }
+}
+
+/// Settings which change how the network is trained but have no effect on
+/// classification, so they are not serialised with the weights.
+#[derive(Clone, Debug, Default)]
+struct TrainingOptions {
+    /// Per class multipliers for the loss of each image, indexed by the usize
+    /// conversion of the MBTI. None weights every class equally.
+    class_weights: Option<Vec<f64>>,
+    /// Whether each epoch oversamples the minority classes so every MBTI is seen
+    /// as often as the most common one.
+    balanced_sampling: bool,
+    /// Whether each batch is augmented with adversarial copies of its images.
+    adversarial: Option<AdversarialTraining>,
+    /// Random transformations applied to each image as it is trained on.
+    augmentation: Option<Augmentation>,
+    /// Whether the images and targets of each batch are blended together in pairs.
+    mixing: Option<Mixing>,
+    /// Whether each batch is trained with DP-SGD.
+    privacy: Option<PrivateTraining>,
+    /// Whether `train` saves the network to localStorage after every epoch.
+    autosave: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct AdversarialTraining {
+    /// Number of adversarial copies to add to each batch per clean image
+    ratio: f64,
+    /// Maximum change to any pixel made by the FGSM perturbation
+    epsilon: f64,
 }
 
 const FIRST_HIDDEN_LAYER_SIZE: usize = 128;
@@ -321,19 +490,36 @@ fn sigmoid<T: Numeric + Real + Copy>(x: T) -> T {
 
 #[wasm_bindgen]
 impl NeuralNetwork {
-    /// Creates a new Neural Network configuration of randomised weights
-    /// and a simple feed forward architecture.
-    pub fn new() -> NeuralNetwork {
-        let weights: String = get_network_weights();
-
-        if weights.as_str() != "" {
-            let network = NeuralNetwork::from_json(&weights.as_str());
-            if network.epochs > 0 {
-                return network;
+    /// Creates a new Neural Network configuration with a simple feed forward
+    /// architecture, using trained weights from the source if there are any and
+    /// randomised weights otherwise.
+    pub fn new(source: WeightSource) -> NeuralNetwork {
+        let saved = match source {
+            WeightSource::Global => {
+                let weights: String = get_network_weights();
+                if weights.as_str() != "" {
+                    Some(format::from_json(&weights.as_str()).map_err(|e| e.to_string()))
+                } else {
+                    None
+                }
+            }
+            WeightSource::LastSaved => match storage::last_saved() {
+                Ok(Some(name)) => Some(storage::load(&name).map_err(|e| e.to_string())),
+                Ok(None) => None,
+                Err(error) => Some(Err(error.to_string())),
+            },
+            WeightSource::Random => None,
+        };
+
+        match saved {
+            Some(Ok(network)) if network.epochs > 0 => return network,
+            Some(Ok(_)) => (),
+            Some(Err(error)) => {
+                log!("Ignoring saved weights: {}", error);
+            }
+            None => {
+                log!("No weights found");
             }
-        }
-        else {
-            log!("No weights found");
         }
         let mut weights = vec![
             Matrix::empty(0.0, (WIDTH * HEIGHT, FIRST_HIDDEN_LAYER_SIZE)),
@@ -347,34 +533,155 @@ impl NeuralNetwork {
                 }
             }
         }
-        NeuralNetwork {
-            weights,
-            epochs: 0, //buffer: Vec::with_capacity(0),
-        }
+        NeuralNetwork::from_weights(weights)
     }
 
     pub fn layers(&self) -> usize {
         self.weights.len()
     }
 
+    /// Sets a loss multiplier for each of the 16 MBTIs, indexed by the usize conversion
+    /// of the MBTI, which is applied during training. Passing an empty list goes back
+    /// to weighting every class equally. Every weight must be a finite number of at
+    /// least 0.
+    pub fn set_class_weights(&mut self, class_weights: Vec<f64>) -> Result<(), JsValue> {
+        if class_weights.is_empty() {
+            self.options.class_weights = None;
+            return Ok(());
+        }
+        check_class_weights(&class_weights)?;
+        self.options.class_weights = Some(class_weights);
+        Ok(())
+    }
+
+    /// Weights the loss of each MBTI inversely to how often it occurs in the dataset,
+    /// so rare types contribute as much to training as common ones.
+    pub fn use_inverse_frequency_weights(&mut self, training_data: &Dataset) {
+        self.options.class_weights = Some(training_data.inverse_frequency_weights());
+    }
+
+    pub fn class_weights(&self) -> Vec<f64> {
+        self.options.class_weights.clone().unwrap_or_default()
+    }
+
+    /// Enables or disables oversampling of the minority MBTIs within each epoch.
+    pub fn set_balanced_sampling(&mut self, balanced_sampling: bool) {
+        assert!(
+            !balanced_sampling || self.options.privacy.is_none(),
+            "Balanced sampling cannot be combined with differential privacy"
+        );
+        self.options.balanced_sampling = balanced_sampling;
+    }
+
+    /// Augments every training batch with FGSM perturbed copies of some of its
+    /// images, generated against the weights at the start of the batch. `ratio` is
+    /// the number of adversarial copies per clean image, from 0 to 1, and `epsilon`
+    /// is the largest change made to any pixel. A ratio of 0 disables adversarial
+    /// training. Epsilon must be a finite number of at least 0.
+    pub fn set_adversarial_training(&mut self, ratio: f64, epsilon: f64) -> Result<(), JsValue> {
+        self.enable_adversarial_training(ratio, epsilon)
+            .map_err(JsValue::from)
+    }
+
+    /// Randomly transforms every image as it is trained on, see Augmentation. The
+    /// transformations are reproducible from the augmentation's seed, the epoch and
+    /// the index of the image in the dataset.
+    pub fn set_augmentation(&mut self, augmentation: &Augmentation) {
+        self.options.augmentation = Some(augmentation.clone());
+    }
+
+    pub fn disable_augmentation(&mut self) {
+        self.options.augmentation = None;
+    }
+
+    /// Blends every image in each training batch with another image from the batch,
+    /// along with their targets. As the loss of 1 minus the prediction for the target
+    /// is linear in the target, a blend taking λ of the first image is trained on λ of
+    /// the loss for the first image's MBTI plus 1 - λ of the loss for the second's,
+    /// rather than towards predicting the blend itself. λ is drawn from a
+    /// Beta(alpha, alpha) distribution, so small alphas mostly keep one image and an
+    /// alpha of 1 is uniform.
+    pub fn set_mixing(&mut self, method: MixingMethod, alpha: f64) {
+        assert!(alpha > 0.0, "Alpha must be positive");
+        assert!(
+            self.options.privacy.is_none(),
+            "Mixing cannot be combined with differential privacy"
+        );
+        self.options.mixing = Some(Mixing { method, alpha });
+    }
+
+    pub fn disable_mixing(&mut self) {
+        self.options.mixing = None;
+    }
+
+    /// Trains with DP-SGD, clipping the gradient of every image to an L2 norm of at
+    /// most `clip_norm` and adding Gaussian noise with a standard deviation of
+    /// `noise_multiplier * clip_norm` to the gradient of each batch. The (ε, δ)
+    /// spent is tracked after every epoch for the given `delta`. Balanced sampling,
+    /// adversarial training and mixing combine several images into one gradient or
+    /// see an image more than once an epoch, so they cannot be enabled at the same
+    /// time.
+    pub fn set_differential_privacy(&mut self, clip_norm: f64, noise_multiplier: f64, delta: f64) {
+        assert!(clip_norm > 0.0, "Clip norm must be positive");
+        assert!(noise_multiplier > 0.0, "Noise multiplier must be positive");
+        assert!(
+            delta > 0.0 && delta < 1.0,
+            "Delta must be between 0 and 1"
+        );
+        assert!(
+            !self.options.balanced_sampling
+                && self.options.adversarial.is_none()
+                && self.options.mixing.is_none(),
+            "Differential privacy cannot be combined with balanced sampling, adversarial training or mixing"
+        );
+        self.options.privacy = Some(PrivateTraining {
+            clip_norm,
+            noise_multiplier,
+            delta,
+        });
+    }
+
+    pub fn disable_differential_privacy(&mut self) {
+        self.options.privacy = None;
+    }
+
+    /// Enables or disables saving the network to localStorage as `autosave` after
+    /// every epoch `train` trains, so the last saved network can be resumed with
+    /// `WeightSource::LastSaved`. Autosaving is disabled again if a save fails.
+    pub fn set_autosave(&mut self, autosave: bool) {
+        self.options.autosave = autosave;
+    }
+
+    /// Returns the cumulative ε and δ spent by every epoch trained with DP-SGD, with
+    /// the settings each epoch used, or nothing if no epochs were trained privately.
+    ///
+    /// ε is computed by an accountant which assumes each batch is a Poisson sample
+    /// of the training data, with every image included independently. Training
+    /// instead shuffles the data and splits it into fixed size batches, which the
+    /// accountant does not strictly cover, so ε is an estimate rather than a proven
+    /// bound.
+    pub fn privacy_spent(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.privacy).expect("Failed to serialise privacy budget")
+    }
+
+    /// Returns the average loss on the adversarial images of the last trained epoch,
+    /// or nothing if adversarial training was not enabled. The clean loss is returned
+    /// by `train`.
+    pub fn last_adversarial_loss(&self) -> Option<f64> {
+        self.last_adversarial_loss
+    }
+
     pub fn classify(&self, image: &Image) -> MBTI {
-        let input: Matrix<f64> = image.clone().into();
-        // this neural network is a simple feed forward architecture, so dot product
-        // the input through the network weights and apply the sigmoid activation
-        // function each step, then take softmax to produce an output
-        let output = {
-            let layer1 = (input * &self.weights[0]).map(sigmoid);
-            let layer2 = (layer1 * &self.weights[1]).map(sigmoid);
-            layer2 * &self.weights[2]
-        };
-        let classification = linear_algebra::softmax(output.row_major_iter());
-        // find the index of the largest softmax'd label
-        classification
-            .iter()
+        let output = self.logits(image);
+        // softmax keeps the order of the labels, so the largest softmax'd label is
+        // the largest output, and skipping softmax means NaN outputs from weights
+        // which diverged are ordered by total_cmp instead of panicking
+        output
+            .row_major_iter()
             // find argmax of the output
             .enumerate()
-            .max_by(|(_, a), (_, b)| a.partial_cmp(b).expect("NaN should not be in list"))
-            // convert from usize into a MBTI, by construction classification only has
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            // convert from usize into a MBTI, by construction the output only has
             // 16 elements, so the index will fit into a MBTI
             .map(|(i, _)| i as usize)
             .unwrap()
@@ -382,17 +689,20 @@ impl NeuralNetwork {
             .unwrap()
     }
 
-    /// Trains the neural net for 1 epoch and returns the average loss on the epoch
+    /// Trains the neural net for 1 epoch and returns the average loss on the epoch.
+    /// The loss is NaN if training diverged, in which case the epoch should be
+    /// rolled back before the network is used.
     pub fn train(&mut self, training_data: &Dataset) -> f64 {
-        log_progress(0.0);
-        let history = WengertList::new();
-        let mut training = NeuralNetworkTraining::from(&self, &history, self.epochs);
-        let loss = training.train_epoch(training_data, &history);
-        training.update(self);
-        log_progress(1.0);
-        self.epochs += 1;
-        self.to_storage();
-        loss
+        let loss = self.train_without_saving(training_data);
+        // a diverged network should be rolled back rather than resumed later
+        if self.options.autosave && loss.clean.is_finite() {
+            if let Err(error) = storage::save(storage::AUTOSAVE_NAME, &self.to_json()) {
+                // saving will fail the same way next epoch, so only report it once
+                log!("Could not save the network, autosave disabled: {}", error);
+                self.options.autosave = false;
+            }
+        }
+        loss.clean
     }
 
     /// Computes the accuracy on a dataset and returns the percent correctly classified
@@ -408,30 +718,118 @@ impl NeuralNetwork {
         (correct as f64) / (dataset.images.len() as f64)
     }
 
-    /// Serialises the neural network to a JSON string
+    /// Serialises the neural network to a JSON model file, which records the
+    /// architecture, training hyperparameters, creation time and a checksum of the
+    /// weights alongside the network itself.
     pub fn to_json(&self) -> String {
-        serde_json::to_string(self).expect("Failed to serialise neural network")
+        format::to_json(self)
     }
 
-    /// Deserialises a neural network from a JSON string
-    pub fn from_json(json: &str) -> NeuralNetwork {
+    /// Deserialises a neural network from a JSON model file, or from the bare
+    /// network JSON saved by older versions. Files for a different architecture, with
+    /// weight matrices of the wrong shape, or which fail their checksum are rejected.
+    pub fn from_json(json: &str) -> Result<NeuralNetwork, JsValue> {
         // log!("{}", &json);
-        serde_json::from_str(json)
-            .map_err(|e| JsValue::from_str(&e.to_string()))
-            .unwrap()
+        format::from_json(json).map_err(JsValue::from)
     }
 
-    /// This is used to save the neural network to local storage
-    pub fn to_storage(&self) -> () {
-        let json = self.to_json();
-        log!("{}", json);
-        // TODO: Integration with imutable blockchain storage.
+    /// The SHA-256 checksum of the weights and training state of the network
+    pub fn content_hash(&self) -> String {
+        self.checksum()
     }
+}
 
-    /// This is used to load the neural network from local storage
-    pub fn from_storage(json: &str) -> NeuralNetwork {
-        // TODO: Make this async the weights are loaded from the blockchain.
-        NeuralNetwork::from_json(&json)
+impl NeuralNetwork {
+    /// Trains the neural net for 1 epoch without saving it to localStorage
+    fn train_without_saving(&mut self, training_data: &Dataset) -> TrainingLoss {
+        log_progress(0.0);
+        self.save_checkpoint();
+        let start = now();
+        let history = WengertList::new();
+        let mut training = NeuralNetworkTraining::from(&self, &history, self.epochs);
+        let mut loss = training.train_epoch(training_data, &history);
+        training.update(self);
+        log_progress(1.0);
+        if !self.weights_are_finite() {
+            // the loss can still be finite on the epoch which overflowed the weights
+            loss.clean = f64::NAN;
+        }
+        self.epochs += 1;
+        self.last_adversarial_loss = loss.adversarial;
+        self.history.record_epoch(
+            self.epochs,
+            loss.clean,
+            loss.adversarial,
+            training.learning_rate,
+            now() - start,
+            loss.batches.clone(),
+        );
+        self.measure_checkpoint(CheckpointMetric::Loss, loss.clean);
+        if let Some(privacy) = self.options.privacy {
+            self.spend_privacy(privacy, training_data);
+        }
+        loss
+    }
+
+    /// Creates an untrained network configuration from a list of weight matrices.
+    fn from_weights(weights: Vec<Matrix<f64>>) -> NeuralNetwork {
+        NeuralNetwork {
+            weights,
+            epochs: 0, //buffer: Vec::with_capacity(0),
+            temperature: calibration::default_temperature(),
+            privacy: None,
+            history: TrainingHistory::default(),
+            options: TrainingOptions::default(),
+            last_adversarial_loss: None,
+            checkpoints: Checkpoints::default(),
+        }
+    }
+
+    fn enable_adversarial_training(
+        &mut self,
+        ratio: f64,
+        epsilon: f64,
+    ) -> Result<(), adversarial::AttackError> {
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(adversarial::AttackError::RatioOutOfRange(ratio));
+        }
+        adversarial::check_distance(epsilon, adversarial::AttackError::InvalidEpsilon)?;
+        assert!(
+            ratio == 0.0 || self.options.privacy.is_none(),
+            "Adversarial training cannot be combined with differential privacy"
+        );
+        self.options.adversarial = if ratio > 0.0 {
+            Some(AdversarialTraining { ratio, epsilon })
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Checks no weight has overflowed to infinity or become NaN
+    fn weights_are_finite(&self) -> bool {
+        self.weights
+            .iter()
+            .all(|weights| weights.row_major_iter().all(f64::is_finite))
+    }
+
+    /// Feeds an image through the network, returning the 1x16 output layer before
+    /// softmax is applied.
+    fn logits(&self, image: &Image) -> Matrix<f64> {
+        self.feedforward(image).2
+    }
+
+    /// Feeds an image through the network, returning the outputs of the first and
+    /// second hidden layers and the output layer before softmax is applied.
+    fn feedforward(&self, image: &Image) -> (Matrix<f64>, Matrix<f64>, Matrix<f64>) {
+        let input: Matrix<f64> = image.clone().into();
+        // this neural network is a simple feed forward architecture, so dot product
+        // the input through the network weights and apply the sigmoid activation
+        // function each step, then take softmax to produce an output
+        let layer1 = (input * &self.weights[0]).map(sigmoid);
+        let layer2 = (&layer1 * &self.weights[1]).map(sigmoid);
+        let output = &layer2 * &self.weights[2];
+        (layer1, layer2, output)
     }
 }
 
@@ -458,10 +856,57 @@ impl NeuralNetwork {
 struct NeuralNetworkTraining<'a> {
     weights: Vec<Matrix<Record<'a, f64>>>,
     learning_rate: f64,
+    epoch: i32,
+    options: TrainingOptions,
 }
 
 const BATCH_SIZE: usize = 32;
 
+/// A single image to train on within a batch, either borrowed from the training
+/// data or created during training.
+struct TrainingSample<'b> {
+    image: Cow<'b, Image>,
+    /// The probability the network should predict for each MBTI, indexed by the
+    /// usize conversion of the MBTI. This is 1 for the true label unless the image
+    /// was mixed with another.
+    target: Vec<f64>,
+    adversarial: bool,
+}
+
+/// The learning rate for training a network which has already trained for
+/// `epochs`, which decays every epoch
+fn learning_rate(epochs: i32) -> f64 {
+    LEARNING_RATE * LEARNING_RATE_DISCOUNT_FACTOR.powi(epochs)
+}
+
+/// Creates a target distribution with all the probability on one MBTI
+fn one_hot(label: MBTI) -> Vec<f64> {
+    let mut target = vec![0.0; OUTPUT_LAYER_SIZE];
+    target[usize::from(label)] = 1.0;
+    target
+}
+
+/// Gets what we predicted for a target distribution, the sum of our predictions for
+/// each MBTI weighted by their target probability. For a one hot target this is just
+/// the prediction for the true label.
+fn target_prediction<'a>(classification: &[Record<'a, f64>], target: &[f64]) -> Record<'a, f64> {
+    classification
+        .iter()
+        .zip(target.iter())
+        .filter(|(_, &probability)| probability > 0.0)
+        .map(|(&prediction, &probability)| prediction * probability)
+        .sum()
+}
+
+/// Average losses over part of the training data, split between the images from the
+/// dataset and the adversarial copies made of them, if there were any.
+struct TrainingLoss {
+    clean: f64,
+    adversarial: Option<f64>,
+    /// The clean loss of each batch, in the order they were trained on
+    batches: Vec<f64>,
+}
+
 impl<'a> NeuralNetworkTraining<'a> {
     /// Given a WengertList which will be used exclusively for training this struct,
     /// and an existing configuration for weights, creates a new NeuralNetworkTraining
@@ -485,7 +930,9 @@ impl<'a> NeuralNetworkTraining<'a> {
         }
         NeuralNetworkTraining {
             weights,
-            learning_rate: LEARNING_RATE * LEARNING_RATE_DISCOUNT_FACTOR.powi(epochs),
+            learning_rate: learning_rate(epochs),
+            epoch: epochs,
+            options: configuration.options.clone(),
         }
     }
 
@@ -502,39 +949,123 @@ impl<'a> NeuralNetworkTraining<'a> {
         }
     }
 
+    /// Clears the WengertList so it can record the next batch, and makes the weights
+    /// variables on the now empty list again.
+    fn reset(&mut self, history: &'a WengertList<f64>) {
+        history.clear();
+        self.weights[0].map_mut(Record::do_reset);
+        self.weights[1].map_mut(Record::do_reset);
+        self.weights[2].map_mut(Record::do_reset);
+    }
+
+    /// Creates a copy of an image with the Fast Gradient Sign Method against the
+    /// current weights, moving each pixel by epsilon in the direction that increases
+    /// the loss for the target. The history must be reset before the weights
+    /// are next used for training.
+    fn adversarial_copy(
+        &self,
+        image: &Image,
+        target: &[f64],
+        epsilon: f64,
+        history: &'a WengertList<f64>,
+    ) -> Image {
+        let input = Matrix::from_flat_row_major(
+            (1, WIDTH * HEIGHT),
+            image
+                .data
+                .iter()
+                .map(|&pixel| Record::variable(pixel, history))
+                .collect(),
+        );
+        let output = {
+            let layer1 = (&input * &self.weights[0]).map(sigmoid);
+            let layer2 = (layer1 * &self.weights[1]).map(sigmoid);
+            layer2 * &self.weights[2]
+        };
+        let classification = linear_algebra::softmax(output.row_major_iter());
+        let error = Record::constant(1.0) - target_prediction(&classification, target);
+        let derivatives = error.derivatives();
+        Image {
+            data: input
+                .row_major_iter()
+                .map(|pixel| {
+                    (pixel.number + epsilon * adversarial::sign(derivatives[&pixel]))
+                        .clamp(0.0, 1.0)
+                })
+                .collect(),
+        }
+    }
+
     /// Classification is very similar for training, except we stay in floating point
     /// land so we can backprop the error.
-    /// This function takes an iterator of Images and MBTIs, and updates the weights
+    /// This function returns the error of the current weights on a single image.
+    fn error(&self, image: &Image, target: &[f64]) -> Record<'a, f64> {
+        let input: Matrix<f64> = image.clone().into();
+        // this neural network is a simple feed forward architecture, so dot product
+        // the input through the network weights and apply the sigmoid activation
+        // function each step, then take softmax to produce an output
+        let output = {
+            let i = input.map(|p| Record::constant(p));
+            let layer1 = (i * &self.weights[0]).map(sigmoid);
+            let layer2 = (layer1 * &self.weights[1]).map(sigmoid);
+            layer2 * &self.weights[2]
+        };
+        let classification = linear_algebra::softmax(output.row_major_iter());
+        //let classification = NeuralNetworkTraining::softmax(output.row_major_iter());
+        // Get what we predicted for the true label. To minimise error, we should
+        // have predicted 1
+        let prediction: Record<f64> = target_prediction(&classification, target);
+        // If we predicted 1 for the true label, error is 0, likewise, if
+        // we predicted 0 for the true label, error is 1.
+        let error: Record<f64> = Record::constant(1.0) - prediction;
+        // Scale the error by the weight of the true label so under represented
+        // classes are not drowned out by the common ones. Mixed targets take the
+        // same blend of their labels' weights.
+        match &self.options.class_weights {
+            Some(class_weights) => {
+                let weight: f64 = class_weights
+                    .iter()
+                    .zip(target.iter())
+                    .map(|(w, t)| w * t)
+                    .sum();
+                error * weight
+            }
+            None => error,
+        }
+    }
+
+    /// This function takes an iterator of TrainingSamples, and updates the weights
     /// after getting the errors on the entire batch, returning the average loss for
-    /// the batch.
-    pub fn train<I>(&mut self, batch: I, learning_rate: f64, history: &'a WengertList<f64>) -> f64
+    /// the clean and adversarial samples in the batch.
+    pub fn train<'b, I>(
+        &mut self,
+        batch: I,
+        learning_rate: f64,
+        history: &'a WengertList<f64>,
+    ) -> TrainingLoss
     where
-        I: Iterator<Item = (&'a Image, MBTI)>,
+        I: Iterator<Item = TrainingSample<'b>>,
     {
         let mut errors = Vec::with_capacity(BATCH_SIZE);
-        for (image, label) in batch {
-            let input: Matrix<f64> = image.clone().into();
-            // this neural network is a simple feed forward architecture, so dot product
-            // the input through the network weights and apply the sigmoid activation
-            // function each step, then take softmax to produce an output
-            let output = {
-                let i = input.map(|p| Record::constant(p));
-                let layer1 = (i * &self.weights[0]).map(sigmoid);
-                let layer2 = (layer1 * &self.weights[1]).map(sigmoid);
-                layer2 * &self.weights[2]
-            };
-            let classification = linear_algebra::softmax(output.row_major_iter());
-            //let classification = NeuralNetworkTraining::softmax(output.row_major_iter());
-            // Get what we predicted for the true label. To minimise error, we should
-            // have predicted 1
-            let prediction: Record<f64> = classification[Into::<usize>::into(label)];
-            // If we predicted 1 for the true label, error is 0, likewise, if
-            // we predicted 0 for the true label, error is 1.
-            let error: Record<f64> = Record::constant(1.0) - prediction;
-            errors.push(error);
+        let mut adversarial_errors = Vec::new();
+        for TrainingSample {
+            image,
+            target,
+            adversarial,
+        } in batch
+        {
+            let error = self.error(&image, &target);
+            if adversarial {
+                adversarial_errors.push(error);
+            } else {
+                errors.push(error);
+            }
         }
         let batch_size = errors.len();
-        let error: Record<f64> = errors.drain(..).sum();
+        let adversarial_batch_size = adversarial_errors.len();
+        let clean_error: Record<f64> = errors.drain(..).sum();
+        let adversarial_error: Record<f64> = adversarial_errors.drain(..).sum();
+        let error = clean_error + adversarial_error;
         let derivatives = error.derivatives();
         // update weights to minimise error, note that if error was 0 this
         // trivially does nothing
@@ -542,11 +1073,17 @@ impl<'a> NeuralNetworkTraining<'a> {
         self.weights[1].map_mut(|x| x - (derivatives[&x] * learning_rate));
         self.weights[2].map_mut(|x| x - (derivatives[&x] * learning_rate));
         // reset gradients
-        history.clear();
-        self.weights[0].map_mut(Record::do_reset);
-        self.weights[1].map_mut(Record::do_reset);
-        self.weights[2].map_mut(Record::do_reset);
-        error.number / (batch_size as f64)
+        self.reset(history);
+        let clean = clean_error.number / (batch_size as f64);
+        TrainingLoss {
+            clean,
+            batches: vec![clean],
+            adversarial: if adversarial_batch_size > 0 {
+                Some(adversarial_error.number / (adversarial_batch_size as f64))
+            } else {
+                None
+            },
+        }
     }
 
     /// Performs minibatch SGD for one epoch on all of the training data in a random order,
@@ -555,18 +1092,16 @@ impl<'a> NeuralNetworkTraining<'a> {
         &mut self,
         training_data: &'a Dataset,
         history: &'a WengertList<f64>,
-    ) -> f64 {
-        let random_numbers = EndlessRandomGenerator {};
-        let random_index_order: Vec<usize> = {
-            let mut indexes: Vec<(usize, f64)> = (0..training_data.images.len())
-                .zip(random_numbers)
-                .collect();
-            // sort by the random numbers we zipped
-            indexes.sort_by(|(_, i), (_, j)| i.partial_cmp(j).unwrap());
-            // drop the random numbers in the now randomised list of indexes
-            indexes.drain(..).map(|(x, _)| x).collect()
+    ) -> TrainingLoss {
+        let random_index_order: Vec<usize> = if self.options.balanced_sampling {
+            balanced_index_order(training_data)
+        } else {
+            shuffle((0..training_data.images.len()).collect())
         };
         let mut epoch_losses = 0.0;
+        let mut batches = Vec::with_capacity(random_index_order.len() / BATCH_SIZE + 1);
+        let mut adversarial_losses = 0.0;
+        let mut adversarial_batches = 0;
         let mut batch_losses = 0.0;
         let mut progress = 0;
         let mut i = 0;
@@ -579,13 +1114,60 @@ impl<'a> NeuralNetworkTraining<'a> {
             let end = cmp::min(random_index_order.len(), start + BATCH_SIZE);
             let batch_indexes = &random_index_order[start..end];
             if progress % 5 == 0 {
-                log_progress(i as f64 / (training_data.images.len() as f64));
+                log_progress(i as f64 / (random_index_order.len() as f64));
             }
-            // create a batch of tuples of referenced images and corresponding labels
-            let batch = batch_indexes
+            // create a batch of referenced images and corresponding labels, replacing
+            // the images with transformed copies if augmentation is enabled
+            let mut batch: Vec<TrainingSample> = batch_indexes
                 .iter()
-                .map(|&index| (&training_data.images[index], training_data.labels[index]));
-            let loss = self.train(batch, self.learning_rate, history);
+                .map(|&index| TrainingSample {
+                    image: match &self.options.augmentation {
+                        Some(augmentation) => Cow::Owned(augmentation.augment(
+                            &training_data.images[index],
+                            &[self.epoch as u64, index as u64],
+                        )),
+                        None => Cow::Borrowed(&training_data.images[index]),
+                    },
+                    target: one_hot(training_data.labels[index]),
+                    adversarial: false,
+                })
+                .collect();
+            if let Some(mixing) = self.options.mixing {
+                mixing.mix(&mut batch);
+            }
+            if let Some(AdversarialTraining { ratio, epsilon }) = self.options.adversarial {
+                // the batch is already in a random order, so perturbing the first
+                // images is as good as picking at random
+                let copies = (ratio * batch.len() as f64).round() as usize;
+                let adversarial: Vec<TrainingSample> = batch
+                    .iter()
+                    .take(copies)
+                    .map(|sample| TrainingSample {
+                        image: Cow::Owned(self.adversarial_copy(
+                            &sample.image,
+                            &sample.target,
+                            epsilon,
+                            history,
+                        )),
+                        target: sample.target.clone(),
+                        adversarial: true,
+                    })
+                    .collect();
+                self.reset(history);
+                batch.extend(adversarial);
+            }
+            let loss = match self.options.privacy {
+                Some(privacy) => {
+                    self.train_private(batch.into_iter(), self.learning_rate, history, privacy)
+                }
+                None => self.train(batch.into_iter(), self.learning_rate, history),
+            };
+            if let Some(adversarial_loss) = loss.adversarial {
+                adversarial_losses += adversarial_loss;
+                adversarial_batches += 1;
+            }
+            batches.extend(loss.batches);
+            let loss = loss.clean;
             epoch_losses += loss;
             batch_losses += loss;
             // Report progress to the Web Worker after every 100 images (5 batches
@@ -607,17 +1189,241 @@ impl<'a> NeuralNetworkTraining<'a> {
             }
             i += BATCH_SIZE;
         }
-        epoch_losses / (training_data.images.len() as f64 / BATCH_SIZE as f64)
+        TrainingLoss {
+            clean: epoch_losses / (random_index_order.len() as f64 / BATCH_SIZE as f64),
+            batches,
+            adversarial: if adversarial_batches > 0 {
+                Some(adversarial_losses / adversarial_batches as f64)
+            } else {
+                None
+            },
+        }
     }
 }
 
+/// Milliseconds since the Unix epoch
+#[cfg(target_arch = "wasm32")]
+fn now() -> f64 {
+    js_sys::Date::now()
+}
+
+/// Milliseconds since the Unix epoch
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_millis() as f64)
+        .unwrap_or(0.0)
+}
+
+/// Randomises the order of a list of indexes
+fn shuffle(indexes: Vec<usize>) -> Vec<usize> {
+    let random_numbers = EndlessRandomGenerator {};
+    let mut indexes: Vec<(usize, f64)> = indexes.into_iter().zip(random_numbers).collect();
+    // sort by the random numbers we zipped
+    indexes.sort_by(|(_, i), (_, j)| i.partial_cmp(j).unwrap());
+    // drop the random numbers in the now randomised list of indexes
+    indexes.drain(..).map(|(x, _)| x).collect()
+}
+
+/// Creates a random order of indexes into the dataset in which every MBTI present
+/// appears as many times as the most common one. Each image of a minority class is
+/// repeated as many whole times as fit, and the remainder is made up of a random
+/// selection of that class's images.
+fn balanced_index_order(training_data: &Dataset) -> Vec<usize> {
+    let mut classes: Vec<Vec<usize>> = vec![Vec::new(); OUTPUT_LAYER_SIZE];
+    for (index, &label) in training_data.labels.iter().enumerate() {
+        classes[usize::from(label)].push(index);
+    }
+    let largest = classes.iter().map(|class| class.len()).max().unwrap_or(0);
+    let mut indexes = Vec::with_capacity(largest * OUTPUT_LAYER_SIZE);
+    for class in classes.into_iter().filter(|class| !class.is_empty()) {
+        for _ in 0..(largest / class.len()) {
+            indexes.extend_from_slice(&class);
+        }
+        let remainder = largest % class.len();
+        indexes.extend(shuffle(class).into_iter().take(remainder));
+    }
+    shuffle(indexes)
+}
+
 struct EndlessRandomGenerator {}
 
 impl Iterator for EndlessRandomGenerator {
     type Item = f64;
 
+    #[cfg(target_arch = "wasm32")]
     fn next(&mut self) -> Option<Self::Item> {
         // always return Some, hence this iterator is infinite
         Some(js_sys::Math::random())
     }
+
+    /// Math.random is only available in the browser, so elsewhere, such as in
+    /// tests, the numbers come from a fixed seed
+    #[cfg(not(target_arch = "wasm32"))]
+    fn next(&mut self) -> Option<Self::Item> {
+        thread_local! {
+            static RANDOM: std::cell::RefCell<SeededRandomGenerator> =
+                std::cell::RefCell::new(SeededRandomGenerator::new(0));
+        }
+        Some(RANDOM.with(|random| random.borrow_mut().uniform()))
+    }
+}
+
+/// Draws a number from the standard normal distribution using the Box-Muller
+/// transform on two uniform random numbers.
+fn standard_normal<R: Iterator<Item = f64>>(random: &mut R) -> f64 {
+    // shift the first uniform number into (0, 1] so the log is finite
+    let u1 = 1.0 - random.next().unwrap();
+    let u2 = random.next().unwrap();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// A random number generator which always produces the same sequence of numbers
+/// in the 0-1 range for the same seed, using SplitMix64.
+struct SeededRandomGenerator {
+    state: u64,
+}
+
+impl SeededRandomGenerator {
+    fn new(seed: u64) -> SeededRandomGenerator {
+        SeededRandomGenerator { state: seed }
+    }
+
+    /// Creates a generator for one of many independent streams of numbers that
+    /// should each be reproducible from the same seed, such as one per image.
+    fn from_stream(seed: u64, stream: &[u64]) -> SeededRandomGenerator {
+        let mut generator = SeededRandomGenerator::new(seed);
+        for &part in stream {
+            generator.state = generator.next_u64() ^ part;
+        }
+        SeededRandomGenerator::new(generator.next_u64())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number drawn uniformly from the 0-1 range
+    fn uniform(&mut self) -> f64 {
+        // use the top 53 bits so every f64 in the 0-1 range is equally likely
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a number drawn from the standard normal distribution
+    fn normal(&mut self) -> f64 {
+        standard_normal(self)
+    }
+}
+
+impl Iterator for SeededRandomGenerator {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // always return Some, hence this iterator is infinite
+        Some(self.uniform())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A dataset with an image of each label, whose pixels are all its index
+    pub(crate) fn dataset(labels: &[usize]) -> Dataset {
+        Dataset {
+            images: (0..labels.len())
+                .map(|i| Image {
+                    data: vec![i as f64 / labels.len() as f64; WIDTH * HEIGHT],
+                })
+                .collect(),
+            labels: labels.iter().map(|&label| MBTI::from(label)).collect(),
+        }
+    }
+
+    /// 6 images of ENFP, 3 of ENFJ and 1 of ENTP
+    fn imbalanced_dataset() -> Dataset {
+        dataset(&[0, 0, 0, 0, 0, 0, 1, 1, 1, 2])
+    }
+
+    #[test]
+    fn inverse_frequency_weights_average_to_1_over_present_classes() {
+        let weights = imbalanced_dataset().inverse_frequency_weights();
+        assert_eq!(weights.len(), OUTPUT_LAYER_SIZE);
+        assert!((weights[..3].iter().sum::<f64>() / 3.0 - 1.0).abs() < 1e-12);
+        assert!(weights[3..].iter().all(|&weight| weight == 0.0));
+        // each class contributes equally to the weighted loss
+        assert!((6.0 * weights[0] - weights[2]).abs() < 1e-12);
+        assert!((3.0 * weights[1] - weights[2]).abs() < 1e-12);
+
+        let balanced = dataset(&[3, 5, 3, 5]).inverse_frequency_weights();
+        assert_eq!(balanced[3], 1.0);
+        assert_eq!(balanced[5], 1.0);
+    }
+
+    #[test]
+    fn balanced_order_repeats_every_present_class_equally() {
+        let dataset = imbalanced_dataset();
+        let order = balanced_index_order(&dataset);
+        assert_eq!(order.len(), 3 * 6);
+        let mut counts = [0; OUTPUT_LAYER_SIZE];
+        for &index in order.iter() {
+            counts[usize::from(dataset.labels[index])] += 1;
+        }
+        assert_eq!(&counts[..3], &[6, 6, 6]);
+        assert!(counts[3..].iter().all(|&count| count == 0));
+        // every image of the majority class is seen exactly once
+        for index in 0..6 {
+            assert_eq!(order.iter().filter(|&&i| i == index).count(), 1);
+        }
+    }
+
+    #[test]
+    fn class_weights_must_be_finite_and_not_negative() {
+        let mut weights = vec![1.0; OUTPUT_LAYER_SIZE];
+        check_class_weights(&weights).unwrap();
+        assert!(matches!(
+            check_class_weights(&weights[1..]),
+            Err(ClassWeightError::WrongCount(15))
+        ));
+        for invalid in [-0.5, f64::NAN, f64::INFINITY] {
+            weights[4] = invalid;
+            assert!(matches!(
+                check_class_weights(&weights),
+                Err(ClassWeightError::Invalid { class: 4, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn adversarial_training_reports_a_separate_loss() {
+        let mut network = format::tests::random_network(6);
+        assert!(matches!(
+            network.enable_adversarial_training(0.5, -0.1),
+            Err(adversarial::AttackError::InvalidEpsilon(_))
+        ));
+        assert!(matches!(
+            network.enable_adversarial_training(1.5, 0.1),
+            Err(adversarial::AttackError::RatioOutOfRange(_))
+        ));
+        assert!(network.options.adversarial.is_none());
+
+        let dataset = dataset(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        let loss = network.train_without_saving(&dataset);
+        assert!(loss.adversarial.is_none());
+        assert_eq!(network.last_adversarial_loss(), None);
+
+        network.enable_adversarial_training(0.5, 0.1).unwrap();
+        let loss = network.train_without_saving(&dataset);
+        let adversarial = loss
+            .adversarial
+            .expect("Adversarial loss should be reported");
+        assert!(adversarial.is_finite());
+        assert_ne!(adversarial, loss.clean);
+        assert_eq!(network.last_adversarial_loss(), Some(adversarial));
+    }
 }
@@ -3,6 +3,7 @@ declare global {
     logProgress: (progress: number) => void;
     logBatchLoss: (loss: number) => void;
     getNetworkWeights: () => string;
+    networkStorage: Storage;
   }
 }
 
@@ -0,0 +1,216 @@
+use wasm_bindgen::prelude::*;
+
+use std::borrow::Cow;
+
+use crate::{
+    shuffle, standard_normal, EndlessRandomGenerator, Image, TrainingSample, HEIGHT, WIDTH,
+};
+
+/// How two images in a batch are blended together
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixingMethod {
+    /// Takes a weighted average of every pixel of the two images
+    Mixup = 0,
+    /// Pastes a rectangle from the second image over the first
+    CutMix = 1,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Mixing {
+    pub(crate) method: MixingMethod,
+    pub(crate) alpha: f64,
+}
+
+impl Mixing {
+    /// Blends every sample in the batch with another sample picked by a random
+    /// pairing of the batch, replacing both the image and the target with the blend.
+    /// A single mixing fraction is drawn for the whole batch.
+    pub(crate) fn mix(&self, batch: &mut [TrainingSample]) {
+        let mut random = EndlessRandomGenerator {};
+        let lambda = sample_beta(self.alpha, &mut random);
+        let partners = shuffle((0..batch.len()).collect());
+        let mixed: Vec<(Image, Vec<f64>)> = match self.method {
+            MixingMethod::Mixup => partners
+                .iter()
+                .enumerate()
+                .map(|(i, &j)| {
+                    (
+                        Image {
+                            data: blend(&batch[i].image.data, &batch[j].image.data, lambda),
+                        },
+                        blend(&batch[i].target, &batch[j].target, lambda),
+                    )
+                })
+                .collect(),
+            MixingMethod::CutMix => {
+                let (left, top, width, height) = cut_box(lambda, &mut random);
+                // the box may have been clipped by the edge of the image, so the true
+                // fraction of the first image that remains is recomputed from its area
+                let lambda = 1.0 - (width * height) as f64 / (WIDTH * HEIGHT) as f64;
+                partners
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &j)| {
+                        let mut data = batch[i].image.data.clone();
+                        for y in top..(top + height) {
+                            for x in left..(left + width) {
+                                data[y * WIDTH + x] = batch[j].image.data[y * WIDTH + x];
+                            }
+                        }
+                        (
+                            Image { data },
+                            blend(&batch[i].target, &batch[j].target, lambda),
+                        )
+                    })
+                    .collect()
+            }
+        };
+        for (sample, (image, target)) in batch.iter_mut().zip(mixed) {
+            sample.image = Cow::Owned(image);
+            sample.target = target;
+        }
+    }
+}
+
+/// Takes lambda of each value of a and 1 - lambda of each value of b
+fn blend(a: &[f64], b: &[f64], lambda: f64) -> Vec<f64> {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| lambda * x + (1.0 - lambda) * y)
+        .collect()
+}
+
+/// Picks a rectangle covering 1 - lambda of the image centred on a random pixel,
+/// clipped to the image, returned as left, top, width and height.
+fn cut_box<R: Iterator<Item = f64>>(lambda: f64, random: &mut R) -> (usize, usize, usize, usize) {
+    let ratio = (1.0 - lambda).sqrt();
+    let cut_width = (WIDTH as f64 * ratio).round() as isize;
+    let cut_height = (HEIGHT as f64 * ratio).round() as isize;
+    let centre_x = (random.next().unwrap() * WIDTH as f64) as isize;
+    let centre_y = (random.next().unwrap() * HEIGHT as f64) as isize;
+    let left = (centre_x - cut_width / 2).clamp(0, WIDTH as isize);
+    let right = (centre_x + cut_width / 2).clamp(0, WIDTH as isize);
+    let top = (centre_y - cut_height / 2).clamp(0, HEIGHT as isize);
+    let bottom = (centre_y + cut_height / 2).clamp(0, HEIGHT as isize);
+    (
+        left as usize,
+        top as usize,
+        (right - left) as usize,
+        (bottom - top) as usize,
+    )
+}
+
+/// Draws a sample from a Beta(alpha, alpha) distribution as the ratio of two
+/// Gamma(alpha, 1) samples.
+fn sample_beta<R: Iterator<Item = f64>>(alpha: f64, random: &mut R) -> f64 {
+    let x = sample_gamma(alpha, random);
+    let y = sample_gamma(alpha, random);
+    if x + y == 0.0 {
+        return 0.5;
+    }
+    x / (x + y)
+}
+
+/// Draws a sample from a Gamma(shape, 1) distribution with the Marsaglia and Tsang
+/// method.
+fn sample_gamma<R: Iterator<Item = f64>>(shape: f64, random: &mut R) -> f64 {
+    if shape < 1.0 {
+        // boost the shape above 1 and correct the sample with a uniform power
+        let uniform = 1.0 - random.next().unwrap();
+        return sample_gamma(shape + 1.0, random) * uniform.powf(1.0 / shape);
+    }
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = standard_normal(random);
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let uniform = 1.0 - random.next().unwrap();
+        if uniform.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{one_hot, MBTI, OUTPUT_LAYER_SIZE};
+
+    /// A batch of the images, each labelled with a different MBTI
+    fn batch(images: &[Image]) -> Vec<TrainingSample<'_>> {
+        images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| TrainingSample {
+                image: Cow::Borrowed(image),
+                target: one_hot(MBTI::from(i)),
+                adversarial: false,
+            })
+            .collect()
+    }
+
+    fn plain_images() -> Vec<Image> {
+        (0..OUTPUT_LAYER_SIZE)
+            .map(|i| Image {
+                data: vec![(i + 1) as f64 / (OUTPUT_LAYER_SIZE + 1) as f64; WIDTH * HEIGHT],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mixed_targets_sum_to_1() {
+        let images = plain_images();
+        for method in [MixingMethod::Mixup, MixingMethod::CutMix] {
+            let mixing = Mixing { method, alpha: 0.4 };
+            for _ in 0..10 {
+                let mut batch = batch(&images);
+                mixing.mix(&mut batch);
+                for sample in batch.iter() {
+                    assert!((sample.target.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+                    assert!(sample.target.iter().all(|&p| (0.0..=1.0).contains(&p)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cutmix_target_matches_the_area_kept() {
+        let images = plain_images();
+        let mixing = Mixing {
+            method: MixingMethod::CutMix,
+            alpha: 1.0,
+        };
+        for _ in 0..10 {
+            let mut batch = batch(&images);
+            mixing.mix(&mut batch);
+            for (i, sample) in batch.iter().enumerate() {
+                // pixels still at the original brightness were not pasted over, which
+                // includes every pixel of an image paired with itself
+                let kept = sample
+                    .image
+                    .data
+                    .iter()
+                    .filter(|&&pixel| pixel == images[i].data[0])
+                    .count();
+                let lambda = kept as f64 / (WIDTH * HEIGHT) as f64;
+                assert!((sample.target[i] - lambda).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn beta_samples_are_between_0_and_1() {
+        let mut random = EndlessRandomGenerator {};
+        for alpha in [0.05, 0.2, 1.0, 4.0] {
+            let samples: Vec<f64> = (0..1000).map(|_| sample_beta(alpha, &mut random)).collect();
+            assert!(samples.iter().all(|&x| (0.0..=1.0).contains(&x)));
+            // the distribution is symmetric about a half
+            let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+            assert!((mean - 0.5).abs() < 0.05, "Mean for {} was {}", alpha, mean);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::binary::{Reader, WeightPrecision};
+use crate::calibration::default_temperature;
+use crate::format::{network_from_tensors, ModelFileError, Tensor, LAYER_NAMES};
+use crate::hash::crc32;
+use crate::NeuralNetwork;
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+/// numpy pads the header of each array so the data starts on this alignment
+const NPY_ALIGNMENT: usize = 64;
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+/// Marks a size or offset in a zip header which is stored in the zip64 extra field
+const ZIP64_PLACEHOLDER: u32 = 0xFFFFFFFF;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+/// Zip version 2.0, the minimum needed for stored files in directories
+const ZIP_VERSION: u16 = 20;
+/// 1980-01-01 in MS-DOS date format, the earliest date a zip entry can have
+const ZIP_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Exports the network as a numpy `.npz` archive, which `numpy.load` reads as a
+    /// dictionary with a 2 dimensional array for each weight matrix named
+    /// `first_hidden_layer`, `second_hidden_layer` and `output_layer`, and scalar
+    /// `epochs` and `temperature` arrays.
+    pub fn to_npz(&self, precision: WeightPrecision) -> Vec<u8> {
+        let descr = match precision {
+            WeightPrecision::Single => "<f4",
+            WeightPrecision::Double => "<f8",
+        };
+        let mut files: Vec<(String, Vec<u8>)> = LAYER_NAMES
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(name, matrix)| {
+                let mut array = npy_header(descr, &[matrix.rows(), matrix.columns()]);
+                for weight in matrix.row_major_iter() {
+                    match precision {
+                        WeightPrecision::Single => {
+                            array.extend_from_slice(&(weight as f32).to_le_bytes())
+                        }
+                        WeightPrecision::Double => array.extend_from_slice(&weight.to_le_bytes()),
+                    }
+                }
+                (name.to_string(), array)
+            })
+            .collect();
+        let mut epochs = npy_header("<i4", &[]);
+        epochs.extend_from_slice(&self.epochs.to_le_bytes());
+        files.push(("epochs".to_string(), epochs));
+        let mut temperature = npy_header("<f8", &[]);
+        temperature.extend_from_slice(&self.temperature.to_le_bytes());
+        files.push(("temperature".to_string(), temperature));
+        zip(files
+            .into_iter()
+            .map(|(name, array)| (format!("{}.npy", name), array)))
+    }
+
+    /// Imports a network from a numpy `.npz` archive in the layout written by
+    /// `to_npz`, as saved by `numpy.savez`. The weight arrays may be 32 or 64 bit
+    /// floats and must have the shapes of the network's layers. Compressed archives
+    /// from `numpy.savez_compressed` are not supported.
+    pub fn from_npz(bytes: &[u8]) -> Result<NeuralNetwork, JsValue> {
+        NeuralNetwork::read_npz(bytes).map_err(JsValue::from)
+    }
+}
+
+impl NeuralNetwork {
+    fn read_npz(bytes: &[u8]) -> Result<NeuralNetwork, ModelFileError> {
+        let mut tensors = HashMap::new();
+        for (name, array) in unzip(bytes)? {
+            let name = name.strip_suffix(".npy").unwrap_or(name).to_string();
+            tensors.insert(name, parse_npy(array)?);
+        }
+        let scalar =
+            |tensor: Option<&Tensor>| tensor.and_then(|tensor| tensor.data.first().copied());
+        let epochs = scalar(tensors.get("epochs")).map_or(0, |epochs| epochs as i32);
+        let temperature = scalar(tensors.get("temperature")).unwrap_or_else(default_temperature);
+        network_from_tensors(&mut tensors, epochs, temperature)
+    }
+}
+
+/// Creates the magic string, version and header of a version 1.0 `.npy` file for a
+/// C ordered array, padded so the data which follows it is aligned.
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [length] => format!("({},)", length),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // the magic string, version and header length take 10 bytes, and the header
+    // must end with a newline
+    while (10 + header.len() + 1) % NPY_ALIGNMENT != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+    let mut bytes = Vec::with_capacity(10 + header.len());
+    bytes.extend_from_slice(NPY_MAGIC);
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes
+}
+
+/// Reads a little endian float or integer array from a `.npy` file
+fn parse_npy(bytes: &[u8]) -> Result<Tensor, ModelFileError> {
+    let invalid = |reason: &str| ModelFileError::InvalidArchive(format!("npy file {}", reason));
+    let mut reader = Reader::new(bytes);
+    if reader.take(NPY_MAGIC.len())? != NPY_MAGIC {
+        return Err(invalid("has no magic string"));
+    }
+    let major_version = reader.take(2)?[0];
+    let header_length = match major_version {
+        1 => reader.u16()? as usize,
+        2 | 3 => reader.u32()? as usize,
+        _ => return Err(ModelFileError::UnsupportedVersion(major_version as u32)),
+    };
+    let header = std::str::from_utf8(reader.take(header_length)?)
+        .map_err(|_| invalid("header is not text"))?;
+    let descr = header_value(header, "descr")
+        .and_then(|value| value.split(['\'', '"']).nth(1))
+        .ok_or_else(|| invalid("header has no descr"))?;
+    let fortran_order = header_value(header, "fortran_order")
+        .map(|value| value.starts_with("True"))
+        .unwrap_or(false);
+    let shape = header_value(header, "shape")
+        .and_then(|value| value.strip_prefix('('))
+        .and_then(|value| value.split(')').next())
+        .ok_or_else(|| invalid("header has no shape"))?
+        .split(',')
+        .map(str::trim)
+        .filter(|dimension| !dimension.is_empty())
+        .map(|dimension| dimension.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|_| invalid("shape is not a tuple of integers"))?;
+    let size = match descr {
+        "<f4" | "<i4" => 4,
+        "<f8" | "<i8" => 8,
+        _ => return Err(ModelFileError::UnsupportedDataType(descr.to_string())),
+    };
+    let bytes = shape
+        .iter()
+        .try_fold(size, |bytes: usize, dimension| {
+            bytes.checked_mul(*dimension)
+        })
+        .ok_or_else(|| invalid("shape is too large"))?;
+    let values = reader.take(bytes)?;
+    let mut data: Vec<f64> = values
+        .chunks_exact(size)
+        .map(|value| match descr {
+            "<f4" => f32::from_le_bytes(value.try_into().unwrap()) as f64,
+            "<i4" => i32::from_le_bytes(value.try_into().unwrap()) as f64,
+            "<f8" => f64::from_le_bytes(value.try_into().unwrap()),
+            _ => i64::from_le_bytes(value.try_into().unwrap()) as f64,
+        })
+        .collect();
+    if let (true, [rows, columns]) = (fortran_order, &shape[..]) {
+        // column major data is transposed back into row major order
+        data = (0..rows * columns)
+            .map(|i| data[(i % columns) * rows + i / columns])
+            .collect();
+    } else if fortran_order && shape.len() > 2 {
+        return Err(invalid(
+            "is a Fortran ordered array of more than 2 dimensions",
+        ));
+    }
+    Ok(Tensor { shape, data })
+}
+
+/// Finds the text following a key in the Python dictionary literal of a `.npy`
+/// header
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    ["'", "\""].iter().find_map(|quote| {
+        let key = format!("{}{}{}", quote, key, quote);
+        let start = header.find(&key)? + key.len();
+        header[start..]
+            .trim_start()
+            .strip_prefix(':')
+            .map(str::trim_start)
+    })
+}
+
+/// Stores files in a zip archive without compression, which is how `numpy.savez`
+/// writes `.npz` files
+fn zip<I: Iterator<Item = (String, Vec<u8>)>>(files: I) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut central_directory = Vec::new();
+    let mut entries: u16 = 0;
+    for (name, data) in files {
+        let offset = archive.len() as u32;
+        let crc = crc32(&data);
+        // the fields shared by the local and central headers, from the version
+        // needed to extract to the length of the extra field
+        let mut fields = Vec::with_capacity(26);
+        fields.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // flags
+        fields.extend_from_slice(&0u16.to_le_bytes()); // stored without compression
+        fields.extend_from_slice(&0u16.to_le_bytes()); // modification time
+        fields.extend_from_slice(&ZIP_EPOCH_DATE.to_le_bytes());
+        fields.extend_from_slice(&crc.to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes()); // compressed size
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+
+        archive.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+        archive.extend_from_slice(&fields);
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(&data);
+
+        central_directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+        central_directory.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // made by
+        central_directory.extend_from_slice(&fields);
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        central_directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+        entries += 1;
+    }
+    let central_directory_offset = archive.len() as u32;
+    archive.extend_from_slice(&central_directory);
+    archive.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes()); // disk number
+    archive.extend_from_slice(&0u16.to_le_bytes()); // disk with the central directory
+    archive.extend_from_slice(&entries.to_le_bytes()); // entries on this disk
+    archive.extend_from_slice(&entries.to_le_bytes());
+    archive.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&central_directory_offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    archive
+}
+
+/// Lists the name and data of every file stored without compression in a zip
+/// archive, following the central directory at the end of the archive. Sizes and
+/// offsets in zip64 extra fields, which numpy writes, are supported.
+fn unzip(bytes: &[u8]) -> Result<Vec<(&str, &[u8])>, ModelFileError> {
+    let invalid = |reason: &str| ModelFileError::InvalidArchive(format!("zip {}", reason));
+    // the end of central directory record is 22 bytes plus a variable length comment
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&i| bytes[i..i + 4] == END_OF_CENTRAL_DIRECTORY.to_le_bytes())
+        .ok_or_else(|| invalid("has no end of central directory"))?;
+    let mut reader = Reader::at(bytes, end + 10);
+    let entries = reader.u16()?;
+    reader.u32()?; // size of the central directory
+    let mut reader = Reader::at(bytes, reader.u32()? as usize);
+    let mut files = Vec::with_capacity(entries as usize);
+    for _ in 0..entries {
+        if reader.u32()? != CENTRAL_DIRECTORY_HEADER {
+            return Err(invalid("central directory is corrupt"));
+        }
+        reader.take(6)?; // versions and flags
+        let method = reader.u16()?;
+        reader.take(8)?; // modification time, date and crc
+        let mut compressed_size = reader.u32()? as u64;
+        let mut uncompressed_size = reader.u32()? as u64;
+        let name_length = reader.u16()? as usize;
+        let extra_length = reader.u16()? as usize;
+        let comment_length = reader.u16()? as usize;
+        reader.take(8)?; // disk number and attributes
+        let mut offset = reader.u32()? as u64;
+        let name = std::str::from_utf8(reader.take(name_length)?)
+            .map_err(|_| invalid("file name is not text"))?;
+        let mut extra = Reader::new(reader.take(extra_length)?);
+        reader.take(comment_length)?;
+        while extra.position() + 4 <= extra_length {
+            let id = extra.u16()?;
+            let length = extra.u16()? as usize;
+            let mut field = Reader::new(extra.take(length)?);
+            if id == ZIP64_EXTRA_FIELD {
+                // only the values which overflowed appear, in this order
+                if uncompressed_size == ZIP64_PLACEHOLDER as u64 {
+                    uncompressed_size = field.u64()?;
+                }
+                if compressed_size == ZIP64_PLACEHOLDER as u64 {
+                    compressed_size = field.u64()?;
+                }
+                if offset == ZIP64_PLACEHOLDER as u64 {
+                    offset = field.u64()?;
+                }
+            }
+        }
+        if method != 0 || compressed_size != uncompressed_size {
+            return Err(invalid("files must be stored without compression"));
+        }
+        let mut local = Reader::at(bytes, offset as usize);
+        if local.u32()? != LOCAL_FILE_HEADER {
+            return Err(invalid("local file header is corrupt"));
+        }
+        local.take(22)?; // fields up to the name length
+        let skip = local.u16()? as usize + local.u16()? as usize;
+        local.take(skip)?;
+        files.push((name, local.take(compressed_size as usize)?));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+
+    #[test]
+    fn double_precision_round_trip() {
+        let mut network = random_network(3);
+        network.epochs = 4;
+        network.temperature = 1.0 / 3.0;
+        let loaded = NeuralNetwork::read_npz(&network.to_npz(WeightPrecision::Double)).unwrap();
+        assert_eq!(loaded.weights, network.weights);
+        assert_eq!(loaded.epochs, 4);
+        assert_eq!(loaded.temperature.to_bits(), network.temperature.to_bits());
+    }
+
+    #[test]
+    fn single_precision_round_trip_rounds_weights() {
+        let network = random_network(4);
+        let loaded = NeuralNetwork::read_npz(&network.to_npz(WeightPrecision::Single)).unwrap();
+        for (original, loaded) in network.weights.iter().zip(loaded.weights.iter()) {
+            for (x, y) in original.row_major_iter().zip(loaded.row_major_iter()) {
+                assert_eq!(y, x as f32 as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_fortran_ordered_arrays() {
+        let mut array = npy_header("<i4", &[2, 3]);
+        let header = String::from_utf8(array.split_off(10)).unwrap();
+        array.extend_from_slice(header.replace("False", "True ").as_bytes());
+        for value in [1i32, 4, 2, 5, 3, 6] {
+            array.extend_from_slice(&value.to_le_bytes());
+        }
+        let tensor = parse_npy(&array).unwrap();
+        assert_eq!(tensor.shape, vec![2, 3]);
+        assert_eq!(tensor.data, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn rejects_shapes_which_overflow() {
+        let mut array = npy_header("<f8", &[usize::MAX, 2]);
+        array.extend_from_slice(&[0; 16]);
+        assert!(matches!(
+            parse_npy(&array),
+            Err(ModelFileError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_npy_files() {
+        assert!(matches!(
+            parse_npy(b"NUMPY!\x01\x00\x00\x00"),
+            Err(ModelFileError::InvalidArchive(_))
+        ));
+        let mut array = npy_header("<c16", &[1]);
+        array.extend_from_slice(&[0; 16]);
+        assert!(matches!(
+            parse_npy(&array),
+            Err(ModelFileError::UnsupportedDataType(_))
+        ));
+        let mut array = npy_header("<f8", &[2]);
+        array.extend_from_slice(&[0; 8]);
+        assert!(matches!(
+            parse_npy(&array),
+            Err(ModelFileError::InvalidLength { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_archives() {
+        assert!(matches!(
+            unzip(b"not a zip file at all"),
+            Err(ModelFileError::InvalidArchive(_))
+        ));
+        let mut archive = zip(std::iter::once(("a.npy".to_string(), vec![1, 2, 3])));
+        let end = archive.len() - 22;
+        // point the central directory past the end of the archive
+        archive[end + 16..end + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            unzip(&archive),
+            Err(ModelFileError::InvalidLength { .. })
+        ));
+        let mut archive = zip(std::iter::once(("a.npy".to_string(), vec![1, 2, 3])));
+        let central_directory = archive.len() - 22 - 46 - "a.npy".len();
+        // mark the file as deflated
+        archive[central_directory + 10] = 8;
+        assert!(matches!(
+            unzip(&archive),
+            Err(ModelFileError::InvalidArchive(_))
+        ));
+    }
+}
@@ -0,0 +1,370 @@
+use wasm_bindgen::prelude::*;
+
+use crate::binary::WeightPrecision;
+use crate::format::{layer_shapes, LAYER_NAMES};
+use crate::{NeuralNetwork, MBTI, OUTPUT_LAYER_SIZE};
+
+/// ONNX IR version 7, which introduced opset 13
+const IR_VERSION: i64 = 7;
+/// The operator set the graph uses, chosen as the first where Softmax normalises
+/// over a single axis rather than flattening every axis after it
+const OPSET_VERSION: i64 = 13;
+
+/// `TensorProto.DataType` values
+const FLOAT: i64 = 1;
+const DOUBLE: i64 = 11;
+/// `AttributeProto.AttributeType.INT`
+const INT_ATTRIBUTE: i64 = 2;
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Exports the network as an ONNX model taking a 1x256 `input` of pixels and
+    /// returning a 1x16 `probabilities` output, with the softmax temperature
+    /// applied. The `labels` metadata property lists the MBTI of each output as a
+    /// JSON array.
+    pub fn to_onnx(&self, precision: WeightPrecision) -> Vec<u8> {
+        let data_type = match precision {
+            WeightPrecision::Single => FLOAT,
+            WeightPrecision::Double => DOUBLE,
+        };
+        let mut graph = Message::new().string(2, "myers_briggs_predictor");
+
+        // input -> MatMul -> Sigmoid -> MatMul -> Sigmoid -> MatMul -> logits
+        let mut previous = "input".to_string();
+        for (layer, name) in LAYER_NAMES.iter().enumerate() {
+            let output = format!("{}_output", name);
+            graph = graph.message(1, node("MatMul", &[&previous, name], &output));
+            previous = output;
+            if layer + 1 < LAYER_NAMES.len() {
+                let activated = format!("{}_activation", name);
+                graph = graph.message(1, node("Sigmoid", &[&previous], &activated));
+                previous = activated;
+            }
+        }
+        graph = graph
+            .message(1, node("Div", &[&previous, "temperature"], "scaled_logits"))
+            .message(
+                1,
+                node("Softmax", &["scaled_logits"], "probabilities").message(
+                    5,
+                    Message::new()
+                        .string(1, "axis")
+                        .int(3, -1)
+                        .int(20, INT_ATTRIBUTE),
+                ),
+            );
+
+        for ((name, matrix), &(rows, columns)) in LAYER_NAMES
+            .iter()
+            .zip(self.weights.iter())
+            .zip(layer_shapes().iter())
+        {
+            graph = graph.message(
+                5,
+                tensor(name, &[rows, columns], precision, matrix.row_major_iter()),
+            );
+        }
+        graph = graph
+            .message(
+                5,
+                tensor(
+                    "temperature",
+                    &[],
+                    precision,
+                    std::iter::once(self.temperature),
+                ),
+            )
+            .message(
+                11,
+                value_info("input", data_type, &[1, layer_shapes()[0].0]),
+            )
+            .message(
+                12,
+                value_info("probabilities", data_type, &[1, OUTPUT_LAYER_SIZE]),
+            );
+
+        let labels: Vec<&str> = (0..OUTPUT_LAYER_SIZE)
+            .map(|i| <&str>::from(MBTI::from(i)))
+            .collect();
+        let labels = serde_json::to_string(&labels).expect("Failed to serialise labels");
+        Message::new()
+            .int(1, IR_VERSION)
+            .string(2, env!("CARGO_PKG_NAME"))
+            .string(3, env!("CARGO_PKG_VERSION"))
+            .message(7, graph)
+            .message(8, Message::new().string(1, "").int(2, OPSET_VERSION))
+            .message(14, metadata("labels", &labels))
+            .message(14, metadata("epochs", &self.epochs.to_string()))
+            .into_bytes()
+    }
+}
+
+/// A `NodeProto` applying an operator to named inputs
+fn node(op_type: &str, inputs: &[&str], output: &str) -> Message {
+    let mut node = Message::new();
+    for input in inputs {
+        node = node.string(1, input);
+    }
+    node.string(2, output).string(3, output).string(4, op_type)
+}
+
+/// A `TensorProto` holding constant values in little endian raw data
+fn tensor<I: Iterator<Item = f64>>(
+    name: &str,
+    dimensions: &[usize],
+    precision: WeightPrecision,
+    values: I,
+) -> Message {
+    let mut tensor = Message::new();
+    for &dimension in dimensions {
+        tensor = tensor.int(1, dimension as i64);
+    }
+    let mut data = Vec::new();
+    let data_type = match precision {
+        WeightPrecision::Single => {
+            values.for_each(|value| data.extend_from_slice(&(value as f32).to_le_bytes()));
+            FLOAT
+        }
+        WeightPrecision::Double => {
+            values.for_each(|value| data.extend_from_slice(&value.to_le_bytes()));
+            DOUBLE
+        }
+    };
+    tensor.int(2, data_type).string(8, name).bytes(9, &data)
+}
+
+/// A `ValueInfoProto` describing a graph input or output tensor
+fn value_info(name: &str, data_type: i64, dimensions: &[usize]) -> Message {
+    let mut shape = Message::new();
+    for &dimension in dimensions {
+        shape = shape.message(1, Message::new().int(1, dimension as i64));
+    }
+    let tensor_type = Message::new().int(1, data_type).message(2, shape);
+    Message::new()
+        .string(1, name)
+        .message(2, Message::new().message(1, tensor_type))
+}
+
+/// A `StringStringEntryProto` for the model metadata
+fn metadata(key: &str, value: &str) -> Message {
+    Message::new().string(1, key).string(2, value)
+}
+
+/// Encodes a protobuf message one field at a time
+struct Message {
+    bytes: Vec<u8>,
+}
+
+/// Protobuf wire types
+const VARINT: u64 = 0;
+const LENGTH_DELIMITED: u64 = 2;
+
+impl Message {
+    fn new() -> Message {
+        Message { bytes: Vec::new() }
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        write_varint(&mut self.bytes, (field << 3) | wire_type);
+    }
+
+    /// Writes an int32, int64 or enum field, with negative numbers taking 10 bytes
+    /// as protobuf requires
+    fn int(mut self, field: u64, value: i64) -> Message {
+        self.key(field, VARINT);
+        write_varint(&mut self.bytes, value as u64);
+        self
+    }
+
+    fn bytes(mut self, field: u64, value: &[u8]) -> Message {
+        self.key(field, LENGTH_DELIMITED);
+        write_varint(&mut self.bytes, value.len() as u64);
+        self.bytes.extend_from_slice(value);
+        self
+    }
+
+    fn string(self, field: u64, value: &str) -> Message {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn message(self, field: u64, value: Message) -> Message {
+        self.bytes(field, &value.bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use easy_ml::matrices::Matrix;
+
+    /// A decoded protobuf field, either a varint or a length delimited value
+    #[derive(Debug)]
+    enum Field<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn parse(mut bytes: &[u8]) -> Vec<(u64, Field<'_>)> {
+        fn varint(bytes: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = bytes[0];
+                *bytes = &bytes[1..];
+                value |= ((byte & 0x7F) as u64) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let field = match key & 7 {
+                VARINT => Field::Varint(varint(&mut bytes)),
+                LENGTH_DELIMITED => {
+                    let length = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(length);
+                    bytes = rest;
+                    Field::Bytes(value)
+                }
+                wire_type => panic!("Unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, field));
+        }
+        fields
+    }
+
+    fn messages<'a>(fields: &[(u64, Field<'a>)], number: u64) -> Vec<&'a [u8]> {
+        fields
+            .iter()
+            .filter_map(|(field, value)| match value {
+                Field::Bytes(bytes) if *field == number => Some(*bytes),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn strings<'a>(fields: &[(u64, Field<'a>)], number: u64) -> Vec<&'a str> {
+        messages(fields, number)
+            .into_iter()
+            .map(|bytes| std::str::from_utf8(bytes).unwrap())
+            .collect()
+    }
+
+    fn ints(fields: &[(u64, Field)], number: u64) -> Vec<i64> {
+        fields
+            .iter()
+            .filter_map(|(field, value)| match value {
+                Field::Varint(value) if *field == number => Some(*value as i64),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn network() -> NeuralNetwork {
+        NeuralNetwork::from_weights(
+            layer_shapes()
+                .iter()
+                .map(|&(rows, columns)| {
+                    Matrix::from_flat_row_major(
+                        (rows, columns),
+                        (0..rows * columns).map(|i| (i % 7) as f64 / 7.0).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn exported_model_has_the_network_graph() {
+        let bytes = network().to_onnx(WeightPrecision::Single);
+        let model = parse(&bytes);
+        assert_eq!(ints(&model, 1), vec![IR_VERSION]);
+        let opset = parse(messages(&model, 8)[0]);
+        assert_eq!(ints(&opset, 2), vec![OPSET_VERSION]);
+
+        let graph = parse(messages(&model, 7)[0]);
+        let nodes: Vec<_> = messages(&graph, 1).into_iter().map(parse).collect();
+        let operators: Vec<&str> = nodes.iter().map(|node| strings(node, 4)[0]).collect();
+        assert_eq!(
+            operators,
+            vec!["MatMul", "Sigmoid", "MatMul", "Sigmoid", "MatMul", "Div", "Softmax"]
+        );
+        // every node consumes the output of the one before it
+        for pair in nodes.windows(2) {
+            assert_eq!(strings(&pair[1], 1)[0], strings(&pair[0], 2)[0]);
+        }
+        assert_eq!(strings(&nodes[0], 1), vec!["input", LAYER_NAMES[0]]);
+        assert_eq!(strings(&nodes[6], 2), vec!["probabilities"]);
+
+        let initializers: Vec<_> = messages(&graph, 5).into_iter().map(parse).collect();
+        for (initializer, (name, &(rows, columns))) in initializers
+            .iter()
+            .zip(LAYER_NAMES.iter().zip(layer_shapes().iter()))
+        {
+            assert_eq!(strings(initializer, 8), vec![*name]);
+            assert_eq!(ints(initializer, 1), vec![rows as i64, columns as i64]);
+            assert_eq!(ints(initializer, 2), vec![FLOAT]);
+            assert_eq!(messages(initializer, 9)[0].len(), 4 * rows * columns);
+        }
+        assert_eq!(strings(&initializers[3], 8), vec!["temperature"]);
+        assert_eq!(
+            messages(&initializers[0], 9)[0][4..8],
+            (1.0f32 / 7.0).to_le_bytes()
+        );
+
+        let dimensions = |value_info: &[u8]| -> Vec<i64> {
+            let value_info = parse(value_info);
+            let tensor_type = parse(messages(&parse(messages(&value_info, 2)[0]), 1)[0]);
+            messages(&parse(messages(&tensor_type, 2)[0]), 1)
+                .into_iter()
+                .map(|dimension| ints(&parse(dimension), 1)[0])
+                .collect()
+        };
+        assert_eq!(dimensions(messages(&graph, 11)[0]), vec![1, 256]);
+        assert_eq!(dimensions(messages(&graph, 12)[0]), vec![1, 16]);
+
+        let metadata: Vec<_> = messages(&model, 14).into_iter().map(parse).collect();
+        let labels = metadata
+            .iter()
+            .find(|entry| strings(entry, 1) == vec!["labels"])
+            .map(|entry| strings(entry, 2)[0])
+            .unwrap();
+        let labels: Vec<String> = serde_json::from_str(labels).unwrap();
+        assert_eq!(labels.len(), 16);
+        assert_eq!(labels[0], "ENFP");
+        assert_eq!(labels[15], <&str>::from(MBTI::from(15)));
+    }
+
+    #[test]
+    fn softmax_axis_is_encoded_as_negative_int64() {
+        let bytes = network().to_onnx(WeightPrecision::Double);
+        let graph = parse(messages(&parse(&bytes), 7)[0]);
+        let softmax = messages(&graph, 1)
+            .into_iter()
+            .map(parse)
+            .find(|node| strings(node, 4) == vec!["Softmax"])
+            .unwrap();
+        let attributes: Vec<_> = messages(&softmax, 5).into_iter().map(parse).collect();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(strings(&attributes[0], 1), vec!["axis"]);
+        assert_eq!(ints(&attributes[0], 20), vec![INT_ATTRIBUTE]);
+        // -1 only decodes back as an int64 if it was written as a 10 byte varint
+        assert_eq!(ints(&attributes[0], 3), vec![-1]);
+    }
+}
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+
+use easy_ml::differentiation::WengertList;
+use easy_ml::matrices::Matrix;
+
+use crate::{
+    standard_normal, Dataset, EndlessRandomGenerator, NeuralNetwork, NeuralNetworkTraining,
+    TrainingLoss, TrainingSample, BATCH_SIZE,
+};
+
+/// The Rényi divergence orders the accountant tracks, the tightest of which is
+/// used to convert to an (ε, δ) guarantee.
+const RDP_ORDERS: std::ops::RangeInclusive<u32> = 2..=256;
+
+/// Settings for DP-SGD, which bounds how much any one training image can change
+/// the weights and hides that change in Gaussian noise.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PrivateTraining {
+    /// The largest L2 norm the gradient of any one image is allowed to have
+    pub(crate) clip_norm: f64,
+    /// Standard deviation of the noise added to each batch gradient, as a multiple
+    /// of the clip norm
+    pub(crate) noise_multiplier: f64,
+    /// Probability the (ε, δ) guarantee reported for the network does not hold
+    pub(crate) delta: f64,
+}
+
+/// A run of DP-SGD steps which all used the same settings
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivateSteps {
+    clip_norm: f64,
+    noise_multiplier: f64,
+    /// The fraction of the training data in each batch
+    sampling_rate: f64,
+    steps: u64,
+}
+
+/// The cumulative privacy cost of every epoch trained with DP-SGD, which is saved
+/// with the network so training can resume without losing track of it.
+///
+/// The guarantee only covers the training data if every epoch trained on it used
+/// DP-SGD, so `epochs` should be compared with the epochs of the network. It is
+/// also computed as if batches were Poisson sampled, see `privacy_spent`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyBudget {
+    epsilon: f64,
+    delta: f64,
+    /// The number of epochs trained with DP-SGD
+    epochs: i32,
+    steps: Vec<PrivateSteps>,
+}
+
+impl PrivacyBudget {
+    /// Adds an epoch of DP-SGD on a dataset of `images` images to the budget and
+    /// recomputes ε for the delta of the latest settings.
+    fn record_epoch(&mut self, privacy: PrivateTraining, images: usize) {
+        let sampling_rate = (BATCH_SIZE as f64 / images as f64).min(1.0);
+        let run = PrivateSteps {
+            clip_norm: privacy.clip_norm,
+            noise_multiplier: privacy.noise_multiplier,
+            sampling_rate,
+            steps: images.div_ceil(BATCH_SIZE) as u64,
+        };
+        let settings =
+            |run: &PrivateSteps| (run.clip_norm, run.noise_multiplier, run.sampling_rate);
+        match self.steps.last_mut() {
+            Some(last) if settings(last) == settings(&run) => last.steps += run.steps,
+            _ => self.steps.push(run),
+        }
+        self.epochs += 1;
+        self.delta = privacy.delta;
+        self.epsilon = epsilon(&self.steps, self.delta);
+    }
+
+    /// The budget of whichever client spent the most in a round of federated
+    /// learning. Every client trains on its own images, so each image is covered by
+    /// its client's guarantee and the averaged network by the weakest of them.
+    pub(crate) fn largest<I>(budgets: I) -> Option<PrivacyBudget>
+    where
+        I: IntoIterator<Item = Option<PrivacyBudget>>,
+    {
+        budgets
+            .into_iter()
+            .flatten()
+            .max_by(|a, b| a.epsilon.total_cmp(&b.epsilon))
+    }
+}
+
+impl NeuralNetwork {
+    /// Adds an epoch of DP-SGD to the privacy budget of the network.
+    pub(crate) fn spend_privacy(&mut self, privacy: PrivateTraining, training_data: &Dataset) {
+        let budget = self.privacy.get_or_insert_with(|| PrivacyBudget {
+            epsilon: 0.0,
+            delta: privacy.delta,
+            epochs: 0,
+            steps: Vec::new(),
+        });
+        budget.record_epoch(privacy, training_data.images.len());
+    }
+}
+
+impl<'a> NeuralNetworkTraining<'a> {
+    /// Trains on a batch with DP-SGD. The gradient of each image is computed on its
+    /// own and scaled down to at most the clip norm, then the sum of the clipped
+    /// gradients has Gaussian noise added before it is used to update the weights.
+    pub(crate) fn train_private<'b, I>(
+        &mut self,
+        batch: I,
+        learning_rate: f64,
+        history: &'a WengertList<f64>,
+        privacy: PrivateTraining,
+    ) -> TrainingLoss
+    where
+        I: Iterator<Item = TrainingSample<'b>>,
+    {
+        let mut gradients: Vec<Matrix<f64>> = self
+            .weights
+            .iter()
+            .map(|weights| Matrix::empty(0.0, weights.size()))
+            .collect();
+        let mut loss = 0.0;
+        let mut batch_size = 0;
+        for sample in batch {
+            let error = self.error(&sample.image, &sample.target);
+            let derivatives = error.derivatives();
+            let example: Vec<Matrix<f64>> = self
+                .weights
+                .iter()
+                .map(|weights| weights.map(|x| derivatives[&x]))
+                .collect();
+            let norm = example
+                .iter()
+                .flat_map(|gradient| gradient.row_major_iter())
+                .map(|x| x * x)
+                .sum::<f64>()
+                .sqrt();
+            let scale = if norm > privacy.clip_norm {
+                privacy.clip_norm / norm
+            } else {
+                1.0
+            };
+            for (sum, gradient) in gradients.iter_mut().zip(example.iter()) {
+                *sum = &*sum + gradient.map(|x| x * scale);
+            }
+            loss += error.number;
+            batch_size += 1;
+            self.reset(history);
+        }
+        let mut random = EndlessRandomGenerator {};
+        let deviation = privacy.noise_multiplier * privacy.clip_norm;
+        for gradient in gradients.iter_mut() {
+            let (rows, columns) = gradient.size();
+            let noise = Matrix::from_flat_row_major(
+                (rows, columns),
+                (0..rows * columns)
+                    .map(|_| deviation * standard_normal(&mut random))
+                    .collect(),
+            );
+            *gradient = &*gradient + noise;
+        }
+        for (weights, gradient) in self.weights.iter_mut().zip(gradients.iter()) {
+            weights
+                .map_mut_with_index(|x, row, column| x - gradient.get(row, column) * learning_rate);
+        }
+        self.reset(history);
+        let clean = loss / batch_size as f64;
+        TrainingLoss {
+            clean,
+            adversarial: None,
+            batches: vec![clean],
+        }
+    }
+}
+
+/// The Rényi differential privacy of one step of the Gaussian mechanism on a
+/// Poisson subsample of the data, for an integer order, from Mironov, Talwar and
+/// Zhang, "Rényi Differential Privacy of the Sampled Gaussian Mechanism" (2019).
+fn sampled_gaussian_rdp(sampling_rate: f64, noise_multiplier: f64, order: u32) -> f64 {
+    if sampling_rate <= 0.0 {
+        return 0.0;
+    }
+    let alpha = order as f64;
+    let variance = noise_multiplier * noise_multiplier;
+    if sampling_rate >= 1.0 {
+        return alpha / (2.0 * variance);
+    }
+    // log of the sum over k of C(α, k) (1 - q)^(α - k) q^k exp((k² - k) / 2σ²),
+    // accumulated in log space as the terms overflow for large orders
+    let mut log_binomial = 0.0;
+    let mut terms = Vec::with_capacity(order as usize + 1);
+    for k in 0..=order {
+        if k > 0 {
+            log_binomial += ((order - k + 1) as f64 / k as f64).ln();
+        }
+        let k = k as f64;
+        terms.push(
+            log_binomial
+                + (alpha - k) * (-sampling_rate).ln_1p()
+                + k * sampling_rate.ln()
+                + (k * k - k) / (2.0 * variance),
+        );
+    }
+    let largest = terms.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = largest + terms.iter().map(|t| (t - largest).exp()).sum::<f64>().ln();
+    log_sum / (alpha - 1.0)
+}
+
+/// Composes every run of steps with the RDP accountant and converts to the
+/// smallest ε which holds with probability 1 - δ, using the conversion from
+/// Balle et al., "Hypothesis Testing Interpretations and Renyi Differential
+/// Privacy" (2020).
+fn epsilon(steps: &[PrivateSteps], delta: f64) -> f64 {
+    RDP_ORDERS
+        .map(|order| {
+            let alpha = order as f64;
+            let rdp: f64 = steps
+                .iter()
+                .map(|run| {
+                    run.steps as f64
+                        * sampled_gaussian_rdp(run.sampling_rate, run.noise_multiplier, order)
+                })
+                .sum();
+            rdp + ((alpha - 1.0) / alpha).ln() - (delta.ln() + alpha.ln()) / (alpha - 1.0)
+        })
+        .fold(f64::INFINITY, f64::min)
+        .max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private_training(noise_multiplier: f64) -> PrivateTraining {
+        PrivateTraining {
+            clip_norm: 1.0,
+            noise_multiplier,
+            delta: 1e-5,
+        }
+    }
+
+    fn empty_budget() -> PrivacyBudget {
+        PrivacyBudget {
+            epsilon: 0.0,
+            delta: 1e-5,
+            epochs: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn epsilon_matches_the_reference_accountant() {
+        // DP-SGD on MNIST with batches of 256 for 60 epochs, which an RDP
+        // accountant with the same conversion to (ε, δ) puts at ε ≈ 2.60
+        let run = PrivateSteps {
+            clip_norm: 1.0,
+            noise_multiplier: 1.1,
+            sampling_rate: 256.0 / 60000.0,
+            steps: 60 * 60000 / 256,
+        };
+        let epsilon = epsilon(&[run], 1e-5);
+        assert!((epsilon - 2.60).abs() < 0.01, "ε was {}", epsilon);
+    }
+
+    #[test]
+    fn full_batches_are_the_plain_gaussian_mechanism() {
+        for order in [2, 8, 64] {
+            let expected = order as f64 / (2.0 * 2.0 * 2.0);
+            assert_eq!(sampled_gaussian_rdp(1.0, 2.0, order), expected);
+            assert_eq!(sampled_gaussian_rdp(1.5, 2.0, order), expected);
+            // and the subsampled bound approaches it as the sampling rate nears 1
+            let nearly = sampled_gaussian_rdp(1.0 - 1e-9, 2.0, order);
+            assert!((nearly - expected).abs() < 1e-6);
+        }
+        assert_eq!(sampled_gaussian_rdp(0.0, 2.0, 8), 0.0);
+    }
+
+    #[test]
+    fn epochs_with_the_same_settings_are_merged() {
+        let mut budget = empty_budget();
+        budget.record_epoch(private_training(1.1), 10 * BATCH_SIZE);
+        let one_epoch = budget.epsilon;
+        budget.record_epoch(private_training(1.1), 10 * BATCH_SIZE);
+        assert_eq!(budget.epochs, 2);
+        assert_eq!(budget.steps.len(), 1);
+        assert_eq!(budget.steps[0].steps, 20);
+        assert!(budget.epsilon > one_epoch);
+        assert_eq!(budget.epsilon, epsilon(&budget.steps, 1e-5));
+
+        // a partial final batch is still a step
+        budget.record_epoch(private_training(2.0), 10 * BATCH_SIZE + 1);
+        assert_eq!(budget.epochs, 3);
+        assert_eq!(budget.steps.len(), 2);
+        assert_eq!(budget.steps[1].steps, 11);
+    }
+
+    #[test]
+    fn largest_budget_is_the_one_with_most_epsilon() {
+        let mut small = empty_budget();
+        small.record_epoch(private_training(4.0), 10 * BATCH_SIZE);
+        let mut large = empty_budget();
+        large.record_epoch(private_training(0.8), 10 * BATCH_SIZE);
+        assert_eq!(
+            PrivacyBudget::largest(vec![Some(small.clone()), None, Some(large.clone())]),
+            Some(large)
+        );
+        assert_eq!(PrivacyBudget::largest(vec![None, None]), None);
+    }
+}
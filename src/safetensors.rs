@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+
+use crate::binary::{Reader, WeightPrecision};
+use crate::calibration::default_temperature;
+use crate::format::{network_from_tensors, ModelFileError, Tensor, LAYER_NAMES};
+use crate::NeuralNetwork;
+
+/// The key in the header which holds string metadata instead of a tensor
+const METADATA_KEY: &str = "__metadata__";
+
+/// Where a tensor is in the data following the header
+#[derive(Serialize, Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: (usize, usize),
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Exports the network in the safetensors format, with a tensor for each weight
+    /// matrix named `first_hidden_layer`, `second_hidden_layer` and `output_layer`,
+    /// and the epochs and temperature in the metadata.
+    pub fn to_safetensors(&self, precision: WeightPrecision) -> Vec<u8> {
+        let dtype = match precision {
+            WeightPrecision::Single => "F32",
+            WeightPrecision::Double => "F64",
+        };
+        let mut header = BTreeMap::new();
+        let mut data = Vec::new();
+        for (name, matrix) in LAYER_NAMES.iter().zip(self.weights.iter()) {
+            let start = data.len();
+            for weight in matrix.row_major_iter() {
+                match precision {
+                    WeightPrecision::Single => {
+                        data.extend_from_slice(&(weight as f32).to_le_bytes())
+                    }
+                    WeightPrecision::Double => data.extend_from_slice(&weight.to_le_bytes()),
+                }
+            }
+            let info = TensorInfo {
+                dtype: dtype.to_string(),
+                shape: vec![matrix.rows(), matrix.columns()],
+                data_offsets: (start, data.len()),
+            };
+            header.insert(
+                name.to_string(),
+                serde_json::to_value(info).expect("Failed to serialise tensor info"),
+            );
+        }
+        let mut metadata = BTreeMap::new();
+        metadata.insert("epochs", self.epochs.to_string());
+        metadata.insert("temperature", self.temperature.to_string());
+        header.insert(
+            METADATA_KEY.to_string(),
+            serde_json::to_value(metadata).expect("Failed to serialise metadata"),
+        );
+        let mut header = serde_json::to_string(&header).expect("Failed to serialise header");
+        // pad the header so the tensor data is 8 byte aligned
+        while header.len() % 8 != 0 {
+            header.push(' ');
+        }
+        let mut bytes = Vec::with_capacity(8 + header.len() + data.len());
+        bytes.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    /// Imports a network from the safetensors format in the layout written by
+    /// `to_safetensors`. The weight tensors may be F32 or F64 and must have the
+    /// shapes of the network's layers, and any other tensors are ignored.
+    pub fn from_safetensors(bytes: &[u8]) -> Result<NeuralNetwork, JsValue> {
+        NeuralNetwork::read_safetensors(bytes).map_err(JsValue::from)
+    }
+}
+
+impl NeuralNetwork {
+    fn read_safetensors(bytes: &[u8]) -> Result<NeuralNetwork, ModelFileError> {
+        let mut reader = Reader::new(bytes);
+        let header_length = reader.u64()? as usize;
+        let mut header: HashMap<String, Value> =
+            serde_json::from_slice(reader.take(header_length)?)?;
+        let data = &bytes[reader.position()..];
+        let metadata: HashMap<String, String> = match header.remove(METADATA_KEY) {
+            Some(metadata) => serde_json::from_value(metadata)?,
+            None => HashMap::new(),
+        };
+        let mut tensors = HashMap::new();
+        for (name, info) in header {
+            let info: TensorInfo = serde_json::from_value(info)?;
+            let size = match info.dtype.as_str() {
+                "F32" => 4,
+                "F64" => 8,
+                // only the weights need to be readable
+                _ if !LAYER_NAMES.contains(&name.as_str()) => continue,
+                _ => return Err(ModelFileError::UnsupportedDataType(info.dtype)),
+            };
+            let (start, end) = info.data_offsets;
+            let length = info
+                .shape
+                .iter()
+                .try_fold(size, |length: usize, dimension| {
+                    length.checked_mul(*dimension)
+                })
+                .ok_or_else(|| {
+                    ModelFileError::InvalidArchive(format!("tensor {} is too large", name))
+                })?;
+            if start > end || end > data.len() || end - start != length {
+                return Err(ModelFileError::InvalidArchive(format!(
+                    "tensor {} has data offsets outside of the file",
+                    name
+                )));
+            }
+            let values = data[start..end]
+                .chunks_exact(size)
+                .map(|value| match size {
+                    4 => f32::from_le_bytes(value.try_into().unwrap()) as f64,
+                    _ => f64::from_le_bytes(value.try_into().unwrap()),
+                })
+                .collect();
+            tensors.insert(
+                name,
+                Tensor {
+                    shape: info.shape,
+                    data: values,
+                },
+            );
+        }
+        let epochs = metadata
+            .get("epochs")
+            .and_then(|epochs| epochs.parse().ok())
+            .unwrap_or(0);
+        let temperature = metadata
+            .get("temperature")
+            .and_then(|temperature| temperature.parse().ok())
+            .unwrap_or_else(default_temperature);
+        network_from_tensors(&mut tensors, epochs, temperature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+
+    /// Builds a safetensors file from a header and the data following it
+    fn file(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn double_precision_round_trip() {
+        let mut network = random_network(5);
+        network.epochs = 9;
+        network.temperature = 1.0 / 3.0;
+        let loaded =
+            NeuralNetwork::read_safetensors(&network.to_safetensors(WeightPrecision::Double))
+                .unwrap();
+        assert_eq!(loaded.weights, network.weights);
+        assert_eq!(loaded.epochs, 9);
+        assert_eq!(loaded.temperature.to_bits(), network.temperature.to_bits());
+    }
+
+    #[test]
+    fn single_precision_round_trip_rounds_weights() {
+        let network = random_network(6);
+        let loaded =
+            NeuralNetwork::read_safetensors(&network.to_safetensors(WeightPrecision::Single))
+                .unwrap();
+        for (original, loaded) in network.weights.iter().zip(loaded.weights.iter()) {
+            for (x, y) in original.row_major_iter().zip(loaded.row_major_iter()) {
+                assert_eq!(y, x as f32 as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_header_longer_than_the_file() {
+        let mut bytes = u64::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"{}");
+        assert!(matches!(
+            NeuralNetwork::read_safetensors(&bytes),
+            Err(ModelFileError::InvalidLength { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_header_which_is_not_json() {
+        assert!(matches!(
+            NeuralNetwork::read_safetensors(&file("{not json", &[])),
+            Err(ModelFileError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_shapes_which_overflow() {
+        let header = format!(
+            r#"{{"output_layer":{{"dtype":"F64","shape":[{},2],"data_offsets":[0,16]}}}}"#,
+            usize::MAX
+        );
+        assert!(matches!(
+            NeuralNetwork::read_safetensors(&file(&header, &[0; 16])),
+            Err(ModelFileError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_data_offsets_outside_of_the_file() {
+        let header = r#"{"output_layer":{"dtype":"F64","shape":[1,2],"data_offsets":[0,16]}}"#;
+        assert!(matches!(
+            NeuralNetwork::read_safetensors(&file(header, &[0; 8])),
+            Err(ModelFileError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_weight_types() {
+        let header = r#"{"output_layer":{"dtype":"BF16","shape":[1,2],"data_offsets":[0,4]}}"#;
+        assert!(matches!(
+            NeuralNetwork::read_safetensors(&file(header, &[0; 4])),
+            Err(ModelFileError::UnsupportedDataType(_))
+        ));
+    }
+}
@@ -0,0 +1,667 @@
+//! Secure aggregation of client updates with pairwise masking, after Bonawitz et
+//! al., "Practical Secure Aggregation for Privacy-Preserving Machine Learning"
+//! (2017). Every pair of clients agrees on a seed, and each adds the masks
+//! generated from its seeds to its input with opposite signs to its partner, so
+//! the masks cancel in the sum while each masked input on its own looks random.
+//! Clients also add a mask of their own, and share both their key and their own
+//! mask's seed with Shamir's secret sharing, so the server can remove the masks
+//! of clients which drop out without being able to unmask anyone who didn't.
+//!
+//! This is a simulation of the protocol. The key agreement uses a 61 bit prime
+//! field, the masks come from a non cryptographic generator, and shares are
+//! relayed by the server in the clear rather than encrypted for their recipient,
+//! so it shows how the protocol works but would not protect real updates.
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::federated::{FederatedAverage, ModelUpdate};
+use crate::{now, Dataset, NeuralNetwork, SeededRandomGenerator};
+
+/// The Mersenne prime 2^61 - 1, the order of the field secrets are shared in and
+/// the modulus of the key agreement
+const PRIME: u64 = (1 << 61) - 1;
+/// A primitive root of `PRIME`
+const GENERATOR: u64 = 37;
+/// Inputs are encoded as fixed point numbers with this many fractional bits so
+/// masks can be added and cancelled exactly with wrapping integer arithmetic.
+const FRACTION_BITS: i32 = 32;
+
+/// The reasons a round of secure aggregation can fail
+#[derive(Debug, PartialEq, Eq)]
+pub enum SecureAggregationError {
+    /// Secrets can't be shared so that more shares are needed than there are clients
+    InvalidThreshold {
+        threshold: usize,
+        clients: usize,
+    },
+    DuplicateClient(u32),
+    /// A client which did not share its secrets with the others sent an input
+    UnknownClient(u32),
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// Fewer clients sent an input than are needed to unmask the sum
+    TooFewSurvivors {
+        survivors: usize,
+        threshold: usize,
+    },
+    /// Too few shares of a client's secrets were revealed to reconstruct them
+    TooFewShares {
+        client: u32,
+        shares: usize,
+        threshold: usize,
+    },
+}
+
+impl fmt::Display for SecureAggregationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecureAggregationError::InvalidThreshold { threshold, clients } => write!(
+                f,
+                "A threshold of {} shares is invalid for {} clients",
+                threshold, clients
+            ),
+            SecureAggregationError::DuplicateClient(id) => {
+                write!(f, "Client {} is already registered", id)
+            }
+            SecureAggregationError::UnknownClient(id) => {
+                write!(f, "Client {} did not share its secrets", id)
+            }
+            SecureAggregationError::LengthMismatch { expected, actual } => write!(
+                f,
+                "Expected an input of {} values but found {}",
+                expected, actual
+            ),
+            SecureAggregationError::TooFewSurvivors {
+                survivors,
+                threshold,
+            } => write!(
+                f,
+                "Only {} clients sent inputs but {} are needed",
+                survivors, threshold
+            ),
+            SecureAggregationError::TooFewShares {
+                client,
+                shares,
+                threshold,
+            } => write!(
+                f,
+                "Only {} shares of client {}'s secrets were revealed but {} are needed",
+                shares, client, threshold
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SecureAggregationError {}
+
+impl From<SecureAggregationError> for JsValue {
+    fn from(error: SecureAggregationError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+fn multiply_mod(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) % PRIME as u128) as u64
+}
+
+fn power_mod(mut base: u64, mut exponent: u64) -> u64 {
+    let mut result = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = multiply_mod(result, base);
+        }
+        base = multiply_mod(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// The multiplicative inverse in the field, by Fermat's little theorem
+fn inverse_mod(a: u64) -> u64 {
+    power_mod(a, PRIME - 2)
+}
+
+/// One point on a Shamir secret sharing polynomial, with the secret at x = 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Share {
+    x: u64,
+    y: u64,
+}
+
+/// The shares a client gives another client of its two secrets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SecretShares {
+    private_key: Share,
+    self_seed: Share,
+}
+
+/// The x coordinate of a client's shares, which must never be 0
+fn share_x(id: u32) -> u64 {
+    id as u64 + 1
+}
+
+/// Splits a secret into a share for each x coordinate, any `threshold` of which
+/// can reconstruct it
+fn split_secret(
+    secret: u64,
+    threshold: usize,
+    xs: &[u64],
+    random: &mut SeededRandomGenerator,
+) -> Vec<Share> {
+    let coefficients: Vec<u64> = (1..threshold).map(|_| random.next_u64() % PRIME).collect();
+    xs.iter()
+        .map(|&x| {
+            // evaluate the polynomial with Horner's method
+            let y = coefficients.iter().rev().fold(0, |y, &coefficient| {
+                (multiply_mod(y, x) + coefficient) % PRIME
+            });
+            Share {
+                x,
+                y: (multiply_mod(y, x) + secret) % PRIME,
+            }
+        })
+        .collect()
+}
+
+/// Recovers a secret from shares with distinct x coordinates by Lagrange
+/// interpolation at x = 0
+fn reconstruct_secret(shares: &[Share]) -> u64 {
+    shares.iter().fold(0, |secret, share| {
+        let (numerator, denominator) = shares.iter().filter(|other| other.x != share.x).fold(
+            (1, 1),
+            |(numerator, denominator), other| {
+                (
+                    multiply_mod(numerator, other.x),
+                    multiply_mod(denominator, (other.x + PRIME - share.x) % PRIME),
+                )
+            },
+        );
+        let basis = multiply_mod(numerator, inverse_mod(denominator));
+        (secret + multiply_mod(share.y, basis)) % PRIME
+    })
+}
+
+/// The stream of mask values generated from a seed
+fn mask(seed: u64, length: usize) -> impl Iterator<Item = u64> {
+    let mut random = SeededRandomGenerator::new(seed);
+    (0..length).map(move |_| random.next_u64())
+}
+
+fn encode(value: f64) -> u64 {
+    (value * 2f64.powi(FRACTION_BITS)).round() as i64 as u64
+}
+
+fn decode(value: u64) -> f64 {
+    value as i64 as f64 / 2f64.powi(FRACTION_BITS)
+}
+
+/// A participant in secure aggregation, which only ever sends the server its
+/// input hidden behind masks.
+#[derive(Clone, Debug)]
+pub struct Client {
+    id: u32,
+    seed: u64,
+    private_key: u64,
+    self_seed: u64,
+    /// The public key of every client which shared its secrets, by id
+    public_keys: BTreeMap<u32, u64>,
+    /// The shares of its secrets every client gave this one, by id
+    shares: BTreeMap<u32, SecretShares>,
+}
+
+impl Client {
+    /// Creates a client whose secrets are drawn from a seed, which must not be
+    /// known to anyone else.
+    pub fn new(id: u32, seed: u64) -> Client {
+        let mut random = SeededRandomGenerator::from_stream(seed, &[id as u64]);
+        Client {
+            id,
+            seed,
+            private_key: 1 + random.next_u64() % (PRIME - 2),
+            self_seed: random.next_u64() % PRIME,
+            public_keys: BTreeMap::new(),
+            shares: BTreeMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The Diffie-Hellman public key the other clients agree on a seed with
+    pub fn public_key(&self) -> u64 {
+        power_mod(GENERATOR, self.private_key)
+    }
+
+    /// Splits the private key and self mask seed into a share for every client
+    /// with a public key, including this one, any `threshold` of which can
+    /// reconstruct them. The threshold should be more than half of the clients,
+    /// or the server could claim a client dropped out to one half and survived to
+    /// the other and learn both of its secrets.
+    pub fn share_secrets(
+        &self,
+        public_keys: &BTreeMap<u32, u64>,
+        threshold: usize,
+    ) -> Result<BTreeMap<u32, SecretShares>, SecureAggregationError> {
+        if threshold == 0 || threshold > public_keys.len() {
+            return Err(SecureAggregationError::InvalidThreshold {
+                threshold,
+                clients: public_keys.len(),
+            });
+        }
+        let mut random = SeededRandomGenerator::from_stream(self.seed, &[self.id as u64, 1]);
+        let xs: Vec<u64> = public_keys.keys().map(|&id| share_x(id)).collect();
+        let private_key = split_secret(self.private_key, threshold, &xs, &mut random);
+        let self_seed = split_secret(self.self_seed, threshold, &xs, &mut random);
+        Ok(public_keys
+            .keys()
+            .zip(private_key.into_iter().zip(self_seed))
+            .map(|(&id, (private_key, self_seed))| {
+                (
+                    id,
+                    SecretShares {
+                        private_key,
+                        self_seed,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    /// Receives the shares of every client which shared its secrets, which are
+    /// the clients this one will mask its input against.
+    pub fn receive_shares(
+        &mut self,
+        public_keys: &BTreeMap<u32, u64>,
+        shares: BTreeMap<u32, SecretShares>,
+    ) -> Result<(), SecureAggregationError> {
+        if let Some(&id) = shares.keys().find(|id| !public_keys.contains_key(id)) {
+            return Err(SecureAggregationError::UnknownClient(id));
+        }
+        self.public_keys = shares.keys().map(|&id| (id, public_keys[&id])).collect();
+        self.shares = shares;
+        Ok(())
+    }
+
+    /// Encodes the input as fixed point numbers and adds this client's own mask
+    /// and a pairwise mask for every other client which shared its secrets.
+    pub fn mask(&self, input: &[f64]) -> Vec<u64> {
+        let mut masked: Vec<u64> = input.iter().map(|&x| encode(x)).collect();
+        add_mask(&mut masked, self.self_seed, true);
+        for (&id, &public_key) in self.public_keys.iter() {
+            if id != self.id {
+                let seed = power_mod(public_key, self.private_key);
+                add_mask(&mut masked, seed, self.id < id);
+            }
+        }
+        masked
+    }
+
+    /// Reveals the share of the self mask seed of every client which sent an
+    /// input, and the share of the private key of every client which dropped out.
+    /// A client never reveals both for the same client, so neither mask of a
+    /// client can be removed unless the other is cancelled already.
+    pub fn reveal(&self, survivors: &BTreeSet<u32>) -> BTreeMap<u32, Share> {
+        self.shares
+            .iter()
+            .map(|(&id, shares)| {
+                if survivors.contains(&id) {
+                    (id, shares.self_seed)
+                } else {
+                    (id, shares.private_key)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Adds or subtracts the mask generated from a seed, wrapping around on overflow
+fn add_mask(values: &mut [u64], seed: u64, add: bool) {
+    let length = values.len();
+    for (value, mask) in values.iter_mut().zip(mask(seed, length)) {
+        *value = if add {
+            value.wrapping_add(mask)
+        } else {
+            value.wrapping_sub(mask)
+        };
+    }
+}
+
+/// Coordinates a round of secure aggregation, relaying shares between clients
+/// and learning only the sum of the inputs of the clients which don't drop out.
+#[derive(Clone, Debug)]
+pub struct Server {
+    threshold: usize,
+    length: usize,
+    public_keys: BTreeMap<u32, u64>,
+    /// The shares sent to each client, by recipient and then sender
+    shares: BTreeMap<u32, BTreeMap<u32, SecretShares>>,
+    /// The clients which shared their secrets
+    shared: BTreeSet<u32>,
+    masked: BTreeMap<u32, Vec<u64>>,
+}
+
+impl Server {
+    /// Starts a round summing inputs of `length` values, which needs at least
+    /// `threshold` clients to send inputs.
+    pub fn new(threshold: usize, length: usize) -> Server {
+        Server {
+            threshold,
+            length,
+            public_keys: BTreeMap::new(),
+            shares: BTreeMap::new(),
+            shared: BTreeSet::new(),
+            masked: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, id: u32, public_key: u64) -> Result<(), SecureAggregationError> {
+        if self.public_keys.insert(id, public_key).is_some() {
+            return Err(SecureAggregationError::DuplicateClient(id));
+        }
+        Ok(())
+    }
+
+    pub fn public_keys(&self) -> &BTreeMap<u32, u64> {
+        &self.public_keys
+    }
+
+    /// Accepts the shares a client made of its secrets for relaying to the others
+    pub fn relay_shares(
+        &mut self,
+        from: u32,
+        shares: BTreeMap<u32, SecretShares>,
+    ) -> Result<(), SecureAggregationError> {
+        if !self.public_keys.contains_key(&from) {
+            return Err(SecureAggregationError::UnknownClient(from));
+        }
+        for (to, share) in shares {
+            self.shares.entry(to).or_default().insert(from, share);
+        }
+        self.shared.insert(from);
+        Ok(())
+    }
+
+    /// The shares every client which shared its secrets sent to a client
+    pub fn shares_for(&self, to: u32) -> BTreeMap<u32, SecretShares> {
+        self.shares.get(&to).cloned().unwrap_or_default()
+    }
+
+    pub fn receive_masked(
+        &mut self,
+        from: u32,
+        masked: Vec<u64>,
+    ) -> Result<(), SecureAggregationError> {
+        if !self.shared.contains(&from) {
+            return Err(SecureAggregationError::UnknownClient(from));
+        }
+        if masked.len() != self.length {
+            return Err(SecureAggregationError::LengthMismatch {
+                expected: self.length,
+                actual: masked.len(),
+            });
+        }
+        self.masked.insert(from, masked);
+        Ok(())
+    }
+
+    /// The clients which sent a masked input
+    pub fn survivors(&self) -> BTreeSet<u32> {
+        self.masked.keys().cloned().collect()
+    }
+
+    /// Sums the masked inputs and removes the masks that don't cancel, using the
+    /// shares revealed by each client: the self masks of every survivor, and the
+    /// pairwise masks survivors share with clients which dropped out.
+    pub fn aggregate(
+        &self,
+        revealed: &BTreeMap<u32, BTreeMap<u32, Share>>,
+    ) -> Result<Vec<f64>, SecureAggregationError> {
+        let survivors = self.survivors();
+        if survivors.len() < self.threshold {
+            return Err(SecureAggregationError::TooFewSurvivors {
+                survivors: survivors.len(),
+                threshold: self.threshold,
+            });
+        }
+        let mut sum = vec![0u64; self.length];
+        for masked in self.masked.values() {
+            for (total, value) in sum.iter_mut().zip(masked.iter()) {
+                *total = total.wrapping_add(*value);
+            }
+        }
+        for &client in self.shared.iter() {
+            let shares: Vec<Share> = revealed
+                .values()
+                .filter_map(|shares| shares.get(&client).cloned())
+                .take(self.threshold)
+                .collect();
+            if shares.len() < self.threshold {
+                return Err(SecureAggregationError::TooFewShares {
+                    client,
+                    shares: shares.len(),
+                    threshold: self.threshold,
+                });
+            }
+            let secret = reconstruct_secret(&shares);
+            if survivors.contains(&client) {
+                add_mask(&mut sum, secret, false);
+            } else {
+                // undo the pairwise masks each survivor added for the dropped client
+                for &survivor in survivors.iter() {
+                    let seed = power_mod(self.public_keys[&survivor], secret);
+                    add_mask(&mut sum, seed, survivor > client);
+                }
+            }
+        }
+        Ok(sum.into_iter().map(decode).collect())
+    }
+}
+
+/// Runs a round of secure aggregation in process with a client for each input,
+/// where the clients at the `dropped` indexes share their secrets but drop out
+/// before sending their input. Returns the sum of the other clients' inputs.
+pub fn simulate(
+    inputs: &[Vec<f64>],
+    threshold: usize,
+    dropped: &[usize],
+    seed: u64,
+) -> Result<Vec<f64>, SecureAggregationError> {
+    let length = inputs.first().map(Vec::len).unwrap_or(0);
+    let mut server = Server::new(threshold, length);
+    let mut clients: Vec<Client> = (0..inputs.len())
+        .map(|i| Client::new(i as u32, seed))
+        .collect();
+    for client in clients.iter() {
+        server.register(client.id(), client.public_key())?;
+    }
+    for client in clients.iter() {
+        let shares = client.share_secrets(server.public_keys(), threshold)?;
+        server.relay_shares(client.id(), shares)?;
+    }
+    for client in clients.iter_mut() {
+        client.receive_shares(server.public_keys(), server.shares_for(client.id))?;
+    }
+    for (i, (client, input)) in clients.iter().zip(inputs.iter()).enumerate() {
+        if !dropped.contains(&i) {
+            server.receive_masked(client.id(), client.mask(input))?;
+        }
+    }
+    let survivors = server.survivors();
+    let revealed: BTreeMap<u32, BTreeMap<u32, Share>> = clients
+        .iter()
+        .filter(|client| survivors.contains(&client.id()))
+        .map(|client| (client.id(), client.reveal(&survivors)))
+        .collect();
+    server.aggregate(&revealed)
+}
+
+/// The outcome of one round of federated learning with secure aggregation
+#[derive(Clone, Debug, Serialize)]
+struct SecureRound {
+    /// The final training loss of every client
+    losses: Vec<f64>,
+    /// The clients whose updates were left out of the round
+    dropped: Vec<usize>,
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Simulates federated learning like `simulate_federated`, except the clients'
+    /// updates are combined with secure aggregation so no update is seen on its
+    /// own. Each round `dropouts` clients picked at random drop out after sharing
+    /// their secrets, and their updates are left out of the average. More than half
+    /// the clients must survive each round. Returns the training loss of each
+    /// client and the clients which dropped out in each round.
+    pub fn simulate_secure_federated(
+        &mut self,
+        dataset: &Dataset,
+        clients: usize,
+        rounds: usize,
+        local_epochs: usize,
+        dropouts: usize,
+        seed: u32,
+    ) -> Result<JsValue, JsValue> {
+        let shards = dataset.split(clients, seed as u64).map_err(JsValue::from)?;
+        let mut random = SeededRandomGenerator::new(seed as u64);
+        let mut report = Vec::with_capacity(rounds);
+        for _ in 0..rounds {
+            let start = now();
+            let (updates, losses, privacy) = self.train_clients(&shards, local_epochs);
+            let inputs: Vec<Vec<f64>> = updates.iter().map(ModelUpdate::weighted_values).collect();
+            let mut order: Vec<(usize, f64)> =
+                (0..inputs.len()).map(|i| (i, random.uniform())).collect();
+            order.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+            let mut dropped: Vec<usize> = order.iter().take(dropouts).map(|&(i, _)| i).collect();
+            dropped.sort_unstable();
+            let sum = simulate(&inputs, inputs.len() / 2 + 1, &dropped, random.next_u64())
+                .map_err(JsValue::from)?;
+            let update = ModelUpdate::from_weighted_sum(self, &sum, local_epochs as i32)
+                .map_err(JsValue::from)?;
+            let mut round = FederatedAverage::new(self);
+            round.add_update(&update).map_err(JsValue::from)?;
+            let averaged_losses: Vec<f64> = losses
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !dropped.contains(i))
+                .map(|(_, &loss)| loss)
+                .collect();
+            self.finish_round(&round, &averaged_losses, privacy, now() - start)
+                .map_err(JsValue::from)?;
+            report.push(SecureRound { losses, dropped });
+        }
+        Ok(serde_wasm_bindgen::to_value(&report).expect("Failed to serialise secure rounds"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inputs which are exact in fixed point, so the aggregate can be compared
+    /// with the plain sum exactly
+    fn inputs(clients: usize, length: usize) -> Vec<Vec<f64>> {
+        (0..clients)
+            .map(|i| {
+                (0..length)
+                    .map(|j| ((i * 31 + j * 7) % 97) as f64 / 64.0 - 0.75)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn plain_sum(inputs: &[Vec<f64>], dropped: &[usize]) -> Vec<f64> {
+        let mut sum = vec![0.0; inputs[0].len()];
+        for (i, input) in inputs.iter().enumerate() {
+            if !dropped.contains(&i) {
+                for (total, x) in sum.iter_mut().zip(input.iter()) {
+                    *total += x;
+                }
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn shamir_shares_reconstruct_from_any_threshold() {
+        let mut random = SeededRandomGenerator::new(7);
+        let xs: Vec<u64> = (1..=5).collect();
+        let shares = split_secret(123456789, 3, &xs, &mut random);
+        assert_eq!(reconstruct_secret(&shares[..3]), 123456789);
+        assert_eq!(reconstruct_secret(&shares[2..]), 123456789);
+        assert_eq!(
+            reconstruct_secret(&[shares[0], shares[2], shares[4]]),
+            123456789
+        );
+        assert_ne!(reconstruct_secret(&shares[..2]), 123456789);
+    }
+
+    #[test]
+    fn aggregate_equals_plain_sum() {
+        let inputs = inputs(5, 40);
+        assert_eq!(
+            simulate(&inputs, 3, &[], 11).unwrap(),
+            plain_sum(&inputs, &[])
+        );
+    }
+
+    #[test]
+    fn aggregate_recovers_from_dropouts() {
+        let inputs = inputs(6, 40);
+        let dropped = [1, 4];
+        assert_eq!(
+            simulate(&inputs, 4, &dropped, 12).unwrap(),
+            plain_sum(&inputs, &dropped)
+        );
+        assert_eq!(
+            simulate(&inputs, 4, &[0, 1, 2], 12),
+            Err(SecureAggregationError::TooFewSurvivors {
+                survivors: 3,
+                threshold: 4
+            })
+        );
+    }
+
+    #[test]
+    fn masked_inputs_hide_the_input() {
+        let inputs = inputs(3, 40);
+        let mut server = Server::new(2, 40);
+        let mut clients: Vec<Client> = (0..3).map(|i| Client::new(i, 13)).collect();
+        for client in clients.iter() {
+            server.register(client.id(), client.public_key()).unwrap();
+        }
+        for client in clients.iter() {
+            let shares = client.share_secrets(server.public_keys(), 2).unwrap();
+            server.relay_shares(client.id(), shares).unwrap();
+        }
+        for client in clients.iter_mut() {
+            let shares = server.shares_for(client.id());
+            client.receive_shares(server.public_keys(), shares).unwrap();
+        }
+        let masked: Vec<f64> = clients[0]
+            .mask(&inputs[0])
+            .into_iter()
+            .map(decode)
+            .collect();
+        let unchanged = masked
+            .iter()
+            .zip(inputs[0].iter())
+            .filter(|(a, b)| a == b)
+            .count();
+        assert_eq!(unchanged, 0);
+        assert_eq!(
+            server.receive_masked(0, vec![0; 39]),
+            Err(SecureAggregationError::LengthMismatch {
+                expected: 40,
+                actual: 39
+            })
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+use web_sys::Storage;
+
+use std::fmt;
+
+use crate::format::{self, ModelFileError};
+use crate::NeuralNetwork;
+
+/// Every saved network is stored under this prefix followed by its name, so other
+/// localStorage entries on the same origin are never listed or overwritten.
+const NETWORK_KEY_PREFIX: &str = "myers-briggs-predictor/networks/";
+/// The name `NeuralNetwork::train` saves the network under after every epoch, if
+/// autosaving is enabled
+pub(crate) const AUTOSAVE_NAME: &str = "autosave";
+/// Holds the name of the most recently saved network
+const LAST_SAVED_KEY: &str = "myers-briggs-predictor/last-saved";
+/// Web workers have no localStorage, so the worker sets a global with this name
+/// to an object with the same methods, which keeps the main thread's
+/// localStorage up to date
+const WORKER_STORAGE_GLOBAL: &str = "networkStorage";
+
+/// Where `NeuralNetwork::new` looks for trained weights before falling back to
+/// random ones
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeightSource {
+    /// The JSON returned by the `getNetworkWeights` JavaScript global
+    Global = 0,
+    /// The network most recently saved to localStorage
+    LastSaved = 1,
+    /// Always start from random weights
+    Random = 2,
+}
+
+/// The reasons saving to or loading from localStorage can fail
+#[derive(Debug)]
+pub(crate) enum StorageError {
+    /// There is no localStorage, nor a `networkStorage` global inside a web
+    /// worker, or the user has disabled storage
+    Unavailable,
+    QuotaExceeded {
+        name: String,
+        bytes: usize,
+    },
+    NotFound(String),
+    Model(ModelFileError),
+    Browser(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Unavailable => write!(f, "localStorage is not available"),
+            StorageError::QuotaExceeded { name, bytes } => write!(
+                f,
+                "Saving network {} needs {} bytes, which exceeds the localStorage quota",
+                name, bytes
+            ),
+            StorageError::NotFound(name) => write!(f, "No saved network named {}", name),
+            StorageError::Model(error) => write!(f, "{}", error),
+            StorageError::Browser(message) => write!(f, "localStorage failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<ModelFileError> for StorageError {
+    fn from(error: ModelFileError) -> Self {
+        StorageError::Model(error)
+    }
+}
+
+impl From<StorageError> for JsValue {
+    /// Converts to a JavaScript Error whose name identifies the kind of failure,
+    /// so callers can catch a full quota separately from other errors
+    fn from(error: StorageError) -> Self {
+        let name = match error {
+            StorageError::Unavailable => "StorageUnavailableError",
+            StorageError::QuotaExceeded { .. } => "QuotaExceededError",
+            StorageError::NotFound(_) => "NotFoundError",
+            StorageError::Model(_) => "InvalidModelError",
+            StorageError::Browser(_) => "StorageError",
+        };
+        let js_error = js_sys::Error::new(&error.to_string());
+        js_error.set_name(name);
+        js_error.into()
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Saves the network to localStorage under a name, replacing any network
+    /// already saved with that name, and remembers it as the last saved network.
+    /// Immutable storage of every version is provided by `store::ModelStore`.
+    pub fn to_storage(&self, name: &str) -> Result<(), JsValue> {
+        save(name, &self.to_json()).map_err(JsValue::from)
+    }
+
+    /// Loads a network saved to localStorage by name. Asynchronous backends are
+    /// loaded from with `NeuralNetwork::load_from`.
+    pub fn from_storage(name: &str) -> Result<NeuralNetwork, JsValue> {
+        load(name).map_err(JsValue::from)
+    }
+
+    /// Lists the names of every network saved to localStorage, in alphabetical
+    /// order.
+    pub fn saved_networks() -> Result<JsValue, JsValue> {
+        let names = list().map_err(JsValue::from)?;
+        Ok(serde_wasm_bindgen::to_value(&names).expect("Failed to serialise network names"))
+    }
+
+    /// Deletes a network saved to localStorage, returning false if there was no
+    /// network saved with that name.
+    pub fn delete_from_storage(name: &str) -> Result<bool, JsValue> {
+        delete(name).map_err(JsValue::from)
+    }
+
+    /// The name of the network most recently saved to localStorage, if it has not
+    /// since been deleted.
+    pub fn last_saved_network() -> Result<Option<String>, JsValue> {
+        last_saved().map_err(JsValue::from)
+    }
+}
+
+/// Finds localStorage, or the storage a web worker has been given in its place
+fn local_storage() -> Result<Storage, StorageError> {
+    let global = js_sys::global();
+    // reading localStorage throws if the user has disabled storage
+    ["localStorage", WORKER_STORAGE_GLOBAL]
+        .iter()
+        .filter_map(|name| js_sys::Reflect::get(&global, &JsValue::from_str(name)).ok())
+        .find(JsValue::is_object)
+        .map(JsCast::unchecked_into)
+        .ok_or(StorageError::Unavailable)
+}
+
+/// Checks the name of an exception thrown by the Storage API, which browsers
+/// name differently when the quota is exceeded. The worker's `networkStorage`
+/// throws errors named QuotaExceededError too.
+fn is_quota_exceeded(error_name: Option<&str>) -> bool {
+    matches!(
+        error_name,
+        Some("QuotaExceededError") | Some("NS_ERROR_DOM_QUOTA_REACHED")
+    )
+}
+
+/// Classifies an exception thrown by the Storage API
+fn browser_error(error: JsValue, name: &str, bytes: usize) -> StorageError {
+    let property = |property: &str| {
+        js_sys::Reflect::get(&error, &JsValue::from_str(property))
+            .ok()
+            .and_then(|value| value.as_string())
+    };
+    if is_quota_exceeded(property("name").as_deref()) {
+        StorageError::QuotaExceeded {
+            name: name.to_string(),
+            bytes,
+        }
+    } else {
+        StorageError::Browser(property("message").unwrap_or_else(|| format!("{:?}", error)))
+    }
+}
+
+fn key(name: &str) -> String {
+    format!("{}{}", NETWORK_KEY_PREFIX, name)
+}
+
+pub(crate) fn save(name: &str, json: &str) -> Result<(), StorageError> {
+    let storage = local_storage()?;
+    // localStorage stores strings as UTF-16, so each character takes two bytes
+    let bytes = 2 * (key(name).len() + json.len());
+    storage
+        .set_item(&key(name), json)
+        .map_err(|error| browser_error(error, name, bytes))?;
+    storage
+        .set_item(LAST_SAVED_KEY, name)
+        .map_err(|error| browser_error(error, name, bytes))
+}
+
+pub(crate) fn load(name: &str) -> Result<NeuralNetwork, StorageError> {
+    let json = local_storage()?
+        .get_item(&key(name))
+        .map_err(|error| browser_error(error, name, 0))?
+        .ok_or_else(|| StorageError::NotFound(name.to_string()))?;
+    Ok(format::from_json(&json)?)
+}
+
+pub(crate) fn list() -> Result<Vec<String>, StorageError> {
+    let storage = local_storage()?;
+    let length = storage
+        .length()
+        .map_err(|error| browser_error(error, "", 0))?;
+    let mut names: Vec<String> = (0..length)
+        .filter_map(|i| storage.key(i).ok().flatten())
+        .filter_map(|key| key.strip_prefix(NETWORK_KEY_PREFIX).map(str::to_string))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+pub(crate) fn delete(name: &str) -> Result<bool, StorageError> {
+    let storage = local_storage()?;
+    let existed = storage
+        .get_item(&key(name))
+        .map_err(|error| browser_error(error, name, 0))?
+        .is_some();
+    storage
+        .remove_item(&key(name))
+        .map_err(|error| browser_error(error, name, 0))?;
+    if last_saved()?.as_deref() == Some(name) {
+        storage
+            .remove_item(LAST_SAVED_KEY)
+            .map_err(|error| browser_error(error, name, 0))?;
+    }
+    Ok(existed)
+}
+
+pub(crate) fn last_saved() -> Result<Option<String>, StorageError> {
+    local_storage()?
+        .get_item(LAST_SAVED_KEY)
+        .map_err(|error| browser_error(error, "", 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_errors_are_recognised_by_name() {
+        assert!(is_quota_exceeded(Some("QuotaExceededError")));
+        assert!(is_quota_exceeded(Some("NS_ERROR_DOM_QUOTA_REACHED")));
+        assert!(!is_quota_exceeded(Some("SecurityError")));
+        assert!(!is_quota_exceeded(None));
+    }
+}
@@ -0,0 +1,373 @@
+//! Content addressed persistence for trained networks. A `ModelStore` keeps the
+//! model file of every network saved to it under the checksum of its weights, so
+//! a stored network can never be changed, only superseded by a new one which
+//! records the old one as its parent.
+
+use serde::{Deserialize, Serialize};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+
+use crate::format::{self, iso_timestamp, ModelFileError};
+use crate::{now, NeuralNetwork};
+
+/// What a store records about each network alongside its model file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelEntry {
+    /// The `content_hash` of the network
+    pub hash: String,
+    /// The hash of the network this one was trained from, if any
+    pub parent: Option<String>,
+    /// ISO 8601 time the network was saved
+    pub created: String,
+    pub epochs: i32,
+}
+
+/// The reasons saving to or loading from a store can fail
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound(String),
+    /// The model file stored under a hash does not have that hash
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    Model(ModelFileError),
+    Json(serde_json::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotFound(hash) => write!(f, "No model stored with hash {}", hash),
+            StoreError::HashMismatch { expected, actual } => write!(
+                f,
+                "Model stored as {} has the content hash {}",
+                expected, actual
+            ),
+            StoreError::Model(error) => write!(f, "{}", error),
+            StoreError::Json(error) => write!(f, "Invalid model entry: {}", error),
+            StoreError::Io(error) => write!(f, "Model store failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<ModelFileError> for StoreError {
+    fn from(error: ModelFileError) -> Self {
+        StoreError::Model(error)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(error: serde_json::Error) -> Self {
+        StoreError::Json(error)
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(error: io::Error) -> Self {
+        StoreError::Io(error)
+    }
+}
+
+/// A backend which networks can be saved to and loaded from by content hash.
+///
+/// Stores are append only, so putting a model whose hash is already stored
+/// leaves the existing one in place. The methods are asynchronous so backends
+/// which need network or browser storage requests can be used the same way as
+/// the local ones.
+pub trait ModelStore {
+    /// Stores the model file contents of a network under the hash of its entry
+    fn put(
+        &self,
+        entry: &ModelEntry,
+        contents: &str,
+    ) -> impl Future<Output = Result<(), StoreError>>;
+
+    /// The model file contents stored under a hash
+    fn get(&self, hash: &str) -> impl Future<Output = Result<Option<String>, StoreError>>;
+
+    /// The entry stored under a hash
+    fn entry(&self, hash: &str) -> impl Future<Output = Result<Option<ModelEntry>, StoreError>>;
+
+    /// Every entry in the store, in the order they were saved
+    fn list(&self) -> impl Future<Output = Result<Vec<ModelEntry>, StoreError>>;
+
+    /// The entry stored under a hash followed by each of its ancestors, ending
+    /// with the first network of the lineage or the first one missing from the store.
+    fn history(&self, hash: &str) -> impl Future<Output = Result<Vec<ModelEntry>, StoreError>> {
+        let hash = hash.to_string();
+        async move {
+            let mut history: Vec<ModelEntry> = Vec::new();
+            let mut next = Some(hash);
+            while let Some(hash) = next {
+                // a cycle can only come from a corrupted store, but shouldn't hang
+                if history.iter().any(|entry| entry.hash == hash) {
+                    break;
+                }
+                match self.entry(&hash).await? {
+                    Some(entry) => {
+                        next = entry.parent.clone();
+                        history.push(entry);
+                    }
+                    None => break,
+                }
+            }
+            Ok(history)
+        }
+    }
+}
+
+impl NeuralNetwork {
+    /// Saves the network to a store, recording the hash of the network it was
+    /// trained from, and returns its content hash.
+    pub async fn save_to<S: ModelStore>(
+        &self,
+        store: &S,
+        parent: Option<&str>,
+    ) -> Result<String, StoreError> {
+        let entry = ModelEntry {
+            hash: self.checksum(),
+            parent: parent.map(str::to_string),
+            created: iso_timestamp(now()),
+            epochs: self.epochs,
+        };
+        store.put(&entry, &self.to_json()).await?;
+        Ok(entry.hash)
+    }
+
+    /// Loads a network from a store by content hash, checking the stored model
+    /// file really has that hash.
+    pub async fn load_from<S: ModelStore>(
+        store: &S,
+        hash: &str,
+    ) -> Result<NeuralNetwork, StoreError> {
+        let contents = store
+            .get(hash)
+            .await?
+            .ok_or_else(|| StoreError::NotFound(hash.to_string()))?;
+        let network = format::from_json(&contents)?;
+        let actual = network.checksum();
+        if actual != hash {
+            return Err(StoreError::HashMismatch {
+                expected: hash.to_string(),
+                actual,
+            });
+        }
+        Ok(network)
+    }
+}
+
+/// Keeps models in memory for as long as the store exists
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    models: RefCell<Vec<(ModelEntry, String)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl ModelStore for MemoryStore {
+    async fn put(&self, entry: &ModelEntry, contents: &str) -> Result<(), StoreError> {
+        let mut models = self.models.borrow_mut();
+        if !models.iter().any(|(stored, _)| stored.hash == entry.hash) {
+            models.push((entry.clone(), contents.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .models
+            .borrow()
+            .iter()
+            .find(|(entry, _)| entry.hash == hash)
+            .map(|(_, contents)| contents.clone()))
+    }
+
+    async fn entry(&self, hash: &str) -> Result<Option<ModelEntry>, StoreError> {
+        Ok(self
+            .models
+            .borrow()
+            .iter()
+            .find(|(entry, _)| entry.hash == hash)
+            .map(|(entry, _)| entry.clone()))
+    }
+
+    async fn list(&self) -> Result<Vec<ModelEntry>, StoreError> {
+        Ok(self
+            .models
+            .borrow()
+            .iter()
+            .map(|(entry, _)| entry.clone())
+            .collect())
+    }
+}
+
+/// Keeps models as files in a directory, with `<hash>.json` holding the model
+/// file and `<hash>.entry.json` its entry. File access is synchronous, so this is
+/// intended for tools and tests rather than the browser.
+#[derive(Clone, Debug)]
+pub struct FilesystemStore {
+    directory: PathBuf,
+}
+
+impl FilesystemStore {
+    /// Creates a store in a directory, creating the directory if it doesn't exist
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Result<FilesystemStore, StoreError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(FilesystemStore { directory })
+    }
+
+    fn model_path(&self, hash: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", hash))
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.directory.join(format!("{}.entry.json", hash))
+    }
+}
+
+/// Reads a file, treating a missing file as None
+fn read_optional(path: PathBuf) -> Result<Option<String>, StoreError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+impl ModelStore for FilesystemStore {
+    async fn put(&self, entry: &ModelEntry, contents: &str) -> Result<(), StoreError> {
+        if self.entry_path(&entry.hash).exists() {
+            return Ok(());
+        }
+        // the entry is written last, so a model only appears once it is complete
+        fs::write(self.model_path(&entry.hash), contents)?;
+        fs::write(self.entry_path(&entry.hash), serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<String>, StoreError> {
+        if !self.entry_path(hash).exists() {
+            return Ok(None);
+        }
+        read_optional(self.model_path(hash))
+    }
+
+    async fn entry(&self, hash: &str) -> Result<Option<ModelEntry>, StoreError> {
+        match read_optional(self.entry_path(hash))? {
+            Some(entry) => Ok(Some(serde_json::from_str(&entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<ModelEntry>, StoreError> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.directory)? {
+            let path = file?.path();
+            let is_entry = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(".entry.json"));
+            if is_entry {
+                entries.push(serde_json::from_str::<ModelEntry>(&fs::read_to_string(
+                    path,
+                )?)?);
+            }
+        }
+        // the directory has no order, so fall back to the time each was saved
+        entries.sort_by(|a, b| a.created.cmp(&b.created).then(a.hash.cmp(&b.hash)));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::format::tests::random_network;
+
+    /// Saves a network and a network trained from it, and checks both can be
+    /// listed, loaded and traced back through their history
+    fn saves_and_loads_lineage<S: ModelStore>(store: &S) {
+        block_on(async {
+            let parent = random_network(0);
+            let parent_hash = parent.save_to(store, None).await.unwrap();
+            assert_eq!(parent_hash, parent.content_hash());
+
+            let mut child = random_network(1);
+            child.epochs = 1;
+            child.temperature = 1.0 / 3.0;
+            let child_hash = child.save_to(store, Some(&parent_hash)).await.unwrap();
+            // saving the same network again is a no op
+            child.save_to(store, Some(&parent_hash)).await.unwrap();
+
+            let hashes: Vec<String> = store
+                .list()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.hash)
+                .collect();
+            assert_eq!(hashes.len(), 2);
+            assert!(hashes.contains(&parent_hash));
+            assert!(hashes.contains(&child_hash));
+
+            let loaded = NeuralNetwork::load_from(store, &child_hash).await.unwrap();
+            assert_eq!(loaded.weights, child.weights);
+            assert_eq!(loaded.epochs, 1);
+            assert_eq!(loaded.temperature.to_bits(), child.temperature.to_bits());
+            assert_eq!(loaded.content_hash(), child_hash);
+
+            let history = store.history(&child_hash).await.unwrap();
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[0].hash, child_hash);
+            assert_eq!(history[0].parent.as_ref(), Some(&parent_hash));
+            assert_eq!(history[1].hash, parent_hash);
+            assert_eq!(history[1].parent, None);
+
+            assert!(matches!(
+                NeuralNetwork::load_from(store, "missing").await,
+                Err(StoreError::NotFound(_))
+            ));
+            assert!(store.history("missing").await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn memory_store_saves_and_loads_lineage() {
+        saves_and_loads_lineage(&MemoryStore::new());
+    }
+
+    #[test]
+    fn filesystem_store_saves_and_loads_lineage() {
+        let directory =
+            std::env::temp_dir().join(format!("mbti-model-store-{}", std::process::id()));
+        let store = FilesystemStore::new(&directory).unwrap();
+        saves_and_loads_lineage(&store);
+
+        // a model file swapped for a different network is caught on load
+        let original = random_network(2);
+        let hash = block_on(original.save_to(&store, None)).unwrap();
+        fs::write(store.model_path(&hash), random_network(3).to_json()).unwrap();
+        assert!(matches!(
+            block_on(NeuralNetwork::load_from(&store, &hash)),
+            Err(StoreError::HashMismatch { .. })
+        ));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
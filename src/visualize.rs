@@ -0,0 +1,169 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{EndlessRandomGenerator, NeuralNetwork, HEIGHT, MBTI, OUTPUT_LAYER_SIZE, WIDTH};
+
+/// Magnitude of the uniform noise around mid grey that prototypes start from
+const PROTOTYPE_NOISE: f64 = 0.1;
+
+/// The incoming weights of each first hidden layer unit laid out on the 16x16
+/// input grid, for drawing as a grid of filters.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct WeightFilters {
+    pixels: Vec<f64>,
+    units: Vec<u32>,
+    norms: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl WeightFilters {
+    /// The number of filters, one per first hidden layer unit
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+
+    /// Every filter one after the other, each as 16x16 row major pixels normalised
+    /// to the 0-1 range of an Image, with the most negative weight of the filter
+    /// at 0 and the most positive at 1.
+    pub fn pixels(&self) -> Vec<f64> {
+        self.pixels.clone()
+    }
+
+    /// The index of the first hidden layer unit each filter belongs to
+    pub fn units(&self) -> Vec<u32> {
+        self.units.clone()
+    }
+
+    /// The L2 norm of each filter's weights before normalisation
+    pub fn norms(&self) -> Vec<f64> {
+        self.norms.clone()
+    }
+}
+
+#[wasm_bindgen]
+impl NeuralNetwork {
+    /// Reshapes the incoming weights of every first hidden layer unit into a 16x16
+    /// image, in unit order or from the largest weight norm to the smallest.
+    pub fn first_layer_filters(&self, sort_by_norm: bool) -> WeightFilters {
+        let weights = &self.weights[0];
+        let mut filters: Vec<(usize, f64, Vec<f64>)> = (0..weights.columns())
+            .map(|unit| {
+                let column: Vec<f64> = weights.column_iter(unit).collect();
+                let norm = column.iter().map(|x| x * x).sum::<f64>().sqrt();
+                (unit, norm, column)
+            })
+            .collect();
+        if sort_by_norm {
+            filters.sort_by(|(_, a, _), (_, b, _)| b.total_cmp(a));
+        }
+        let mut pixels = Vec::with_capacity(filters.len() * WIDTH * HEIGHT);
+        for (_, _, column) in filters.iter() {
+            let min = column.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = column.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let range = max - min;
+            pixels.extend(
+                column
+                    .iter()
+                    .map(|x| if range > 0.0 { (x - min) / range } else { 0.5 }),
+            );
+        }
+        WeightFilters {
+            pixels,
+            units: filters.iter().map(|&(unit, _, _)| unit as u32).collect(),
+            norms: filters.iter().map(|&(_, norm, _)| norm).collect(),
+        }
+    }
+
+    /// Creates a 16x16 row major image which the network strongly associates with the
+    /// given MBTI, by starting from noise and repeatedly stepping the pixels in the
+    /// direction that increases the network's output for the MBTI. The `l2` penalty
+    /// keeps pixels from growing without bound and the `smoothness` penalty on
+    /// differences between neighbouring pixels keeps the image from turning into
+    /// high frequency noise. Pixels are kept within the 0-1 range of real heatmaps.
+    pub fn prototype(
+        &self,
+        label: MBTI,
+        iterations: usize,
+        learning_rate: f64,
+        l2: f64,
+        smoothness: f64,
+    ) -> Vec<f64> {
+        let class = usize::from(label);
+        let mut pixels: Vec<f64> = EndlessRandomGenerator {}
+            .take(WIDTH * HEIGHT)
+            .map(|random| 0.5 + PROTOTYPE_NOISE * (2.0 * random - 1.0))
+            .collect();
+        for _ in 0..iterations {
+            let gradient = self.class_gradient(&pixels, class);
+            let smoothing = smoothness_gradient(&pixels);
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let ascent = gradient[i] - 2.0 * l2 * *pixel - smoothness * smoothing[i];
+                *pixel = (*pixel + learning_rate * ascent).clamp(0.0, 1.0);
+            }
+        }
+        pixels
+    }
+
+    /// Creates a prototype image for every MBTI, see `prototype`. The 16 images are
+    /// returned one after the other in the order of the usize conversion of the
+    /// MBTI, each as 16x16 row major pixels.
+    pub fn prototypes(
+        &self,
+        iterations: usize,
+        learning_rate: f64,
+        l2: f64,
+        smoothness: f64,
+    ) -> Vec<f64> {
+        (0..OUTPUT_LAYER_SIZE)
+            .flat_map(|class| {
+                self.prototype(MBTI::from(class), iterations, learning_rate, l2, smoothness)
+            })
+            .collect()
+    }
+}
+
+/// Differentiates the sum of squared differences between every pixel and its
+/// horizontal and vertical neighbours with respect to each pixel.
+fn smoothness_gradient(pixels: &[f64]) -> Vec<f64> {
+    let mut gradient = vec![0.0; WIDTH * HEIGHT];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let i = y * WIDTH + x;
+            if x + 1 < WIDTH {
+                let difference = pixels[i] - pixels[i + 1];
+                gradient[i] += 2.0 * difference;
+                gradient[i + 1] -= 2.0 * difference;
+            }
+            if y + 1 < HEIGHT {
+                let difference = pixels[i] - pixels[i + WIDTH];
+                gradient[i] += 2.0 * difference;
+                gradient[i + WIDTH] -= 2.0 * difference;
+            }
+        }
+    }
+    gradient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::random_network;
+
+    #[test]
+    fn filters_sorted_by_norm_are_largest_first() {
+        let network = random_network(5);
+        let filters = network.first_layer_filters(true);
+        let norms = filters.norms();
+        assert_eq!(norms.len(), network.weights[0].columns());
+        assert!(norms.windows(2).all(|pair| pair[0] >= pair[1]));
+        let mut units = filters.units();
+        units.sort_unstable();
+        assert_eq!(units, (0..norms.len() as u32).collect::<Vec<_>>());
+        assert_eq!(filters.pixels().len(), norms.len() * WIDTH * HEIGHT);
+        assert!(filters.pixels().iter().all(|&x| (0.0..=1.0).contains(&x)));
+    }
+}
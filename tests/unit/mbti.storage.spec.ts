@@ -0,0 +1,45 @@
+import { expect } from "chai";
+import {
+  MirroredStorage,
+  StorageMessage,
+  storageBytes,
+} from "@worker/mbti.storage";
+
+describe("MirroredStorage", () => {
+  it("posts every saved item to the main thread", () => {
+    const posted: StorageMessage[] = [];
+    const storage = new MirroredStorage([], 1000, (message) =>
+      posted.push(message)
+    );
+    storage.setItem("a", "1");
+    expect(storage.getItem("a")).to.equal("1");
+    expect(posted).to.deep.equal([{ storageSet: true, key: "a", value: "1" }]);
+  });
+
+  it("throws a QuotaExceededError and keeps the old item when full", () => {
+    const posted: StorageMessage[] = [];
+    const quota = storageBytes("a", "1234") + storageBytes("b", "12");
+    const storage = new MirroredStorage([["a", "1234"]], quota, (message) =>
+      posted.push(message)
+    );
+    // replacing an item only counts the new value
+    storage.setItem("a", "1234");
+    storage.setItem("b", "12");
+    expect(() => storage.setItem("b", "123"))
+      .to.throw()
+      .with.property("name", "QuotaExceededError");
+    expect(storage.getItem("b")).to.equal("12");
+    expect(posted).to.have.length(2);
+  });
+
+  it("reverts to the value the main thread kept", () => {
+    const storage = new MirroredStorage([["a", "1"]], 1000, () => undefined);
+    storage.setItem("a", "2");
+    storage.setItem("b", "3");
+    storage.revert("a", "1");
+    storage.revert("b", null);
+    expect(storage.getItem("a")).to.equal("1");
+    expect(storage.getItem("b")).to.equal(null);
+    expect(storage.length).to.equal(1);
+  });
+});
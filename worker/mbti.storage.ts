@@ -0,0 +1,98 @@
+/**
+ * Browsers give each origin around 5MB of localStorage, which they count in
+ * UTF-16 code units of 2 bytes each.
+ */
+const LOCAL_STORAGE_QUOTA_BYTES = 5 * 1024 * 1024;
+
+/**
+ * The number of bytes localStorage counts towards the quota for an entry.
+ */
+const storageBytes = (key: string, value: string) =>
+  2 * (key.length + value.length);
+
+type StorageMessage =
+  | { storageSet: true; key: string; value: string }
+  | { storageRemove: true; key: string };
+
+/**
+ * Web workers have no localStorage, so the main thread sends a copy of the
+ * saved networks in its own, which the Rust code reads and writes as
+ * `networkStorage`. Every change is posted back for the main thread to save.
+ *
+ * `quota` is the number of bytes the main thread has left for the saved
+ * networks. Like localStorage, setting an item which would exceed it throws
+ * an error named QuotaExceededError and leaves the storage unchanged.
+ */
+class MirroredStorage {
+  private entries: Map<string, string>;
+  private quota: number;
+  private post: (message: StorageMessage) => void;
+
+  constructor(
+    entries: [string, string][],
+    quota: number,
+    post: (message: StorageMessage) => void
+  ) {
+    this.entries = new Map(entries);
+    this.quota = quota;
+    this.post = post;
+  }
+
+  get length(): number {
+    return this.entries.size;
+  }
+
+  key(index: number): string | null {
+    return Array.from(this.entries.keys())[index] ?? null;
+  }
+
+  getItem(key: string): string | null {
+    return this.entries.get(key) ?? null;
+  }
+
+  setItem(key: string, value: string) {
+    const previous = this.entries.get(key);
+    const used = this.usedBytes();
+    const bytes =
+      used -
+      (previous === undefined ? 0 : storageBytes(key, previous)) +
+      storageBytes(key, value);
+    if (bytes > this.quota) {
+      const error = new Error(
+        `Saving ${key} needs ${bytes} bytes of the ${this.quota} available`
+      );
+      error.name = "QuotaExceededError";
+      throw error;
+    }
+    this.entries.set(key, value);
+    this.post({ storageSet: true, key: key, value: value });
+  }
+
+  removeItem(key: string) {
+    this.entries.delete(key);
+    this.post({ storageRemove: true, key: key });
+  }
+
+  /**
+   * Puts back the value the main thread really has for a key after it failed
+   * to save a change, without posting it back again.
+   */
+  revert(key: string, value: string | null) {
+    if (value === null) {
+      this.entries.delete(key);
+    } else {
+      this.entries.set(key, value);
+    }
+  }
+
+  private usedBytes(): number {
+    let bytes = 0;
+    this.entries.forEach((value, key) => {
+      bytes += storageBytes(key, value);
+    });
+    return bytes;
+  }
+}
+
+export { LOCAL_STORAGE_QUOTA_BYTES, MirroredStorage, storageBytes };
+export type { StorageMessage };
@@ -6,6 +6,7 @@ import {
   clearWeights,
 } from "./data/mbti.network";
 import { Raw, Sample, MBTIDataset } from "./data/mbti.types";
+import { MirroredStorage } from "./mbti.storage";
 if (typeof importScripts === "function") {
   console.log("Worker: Starting, importScripts available.");
 }
@@ -29,6 +30,7 @@ let training: TrainingData;
 let testing: TrainingData;
 
 let memory: WebAssembly.Memory;
+let storage: MirroredStorage | undefined;
 
 // Add functions to DedicatedWorkerGlobalScope
 window.logProgress = (percent: number) => {
@@ -50,10 +52,10 @@ window.getNetworkWeights = () => {
 (async () => {
   wasm_bindgen("pkg/mbti_wasm_bg.wasm").then(async (mbtiWasmModule) => {
     memory = mbtiWasmModule.memory;
-    const { Dataset, Image, NeuralNetwork } = wasm_bindgen;
+    const { Dataset, Image, NeuralNetwork, WeightSource } = wasm_bindgen;
     const trainingDataset = Dataset.new_training();
     const testingDataset = Dataset.new_testing();
-    let network = NeuralNetwork.new();
+    let network = NeuralNetwork.new(WeightSource.Global);
 
     const intoImage = (image: Raw) => {
       const imageWasm = Image.new();
@@ -73,14 +75,33 @@ window.getNetworkWeights = () => {
 
     onmessage = async (event) => {
       const data = event.data;
+      if (data.storage) {
+        storage = new MirroredStorage(
+          data.storage,
+          data.storageQuota,
+          (message) => postMessage(message)
+        );
+        window.networkStorage = storage;
+        // carry on from the network saved after the last epoch trained, if any
+        network = NeuralNetwork.new(WeightSource.LastSaved);
+        network.set_autosave(true);
+      }
+      if (data.storageFailed) {
+        // the main thread could not save a change it was sent, so stop the
+        // mirror claiming it was saved and stop autosaving into a full storage
+        storage?.revert(data.key, data.value);
+        network.set_autosave(false);
+      }
       if (data.checkWeights) {
         const loadWeightsFromJson = data.loadWeightsFromJson;
         if (loadWeightsFromJson) {
           await fetchNetworkWeights();
+          network = NeuralNetwork.new(WeightSource.Global);
         } else {
           clearWeights();
+          network = NeuralNetwork.new(WeightSource.Random);
         }
-        network = NeuralNetwork.new();
+        network.set_autosave(true);
         // Signal that the UI can unblock;
         postMessage({
           loadedWeights: true,
@@ -114,9 +135,33 @@ window.getNetworkWeights = () => {
         console.log("Worker: Training...");
         window.logProgress(0);
         const loss = network.train(trainingDataset);
-        postMessage({ trainedEpoch: true, loss: loss });
+        // a NaN loss means the weights diverged, so undo the epoch before
+        // evaluating them
+        const diverged = !Number.isFinite(loss);
+        if (diverged) {
+          try {
+            network.rollback(1);
+          } catch (error) {
+            console.log("Worker: Could not roll back diverged epoch", error);
+          }
+        }
+        postMessage({ trainedEpoch: true, loss: loss, diverged: diverged });
         postAccuracy();
       }
+      if (data.rollback || data.restoreBest) {
+        console.log("Worker: Restoring checkpoint...");
+        try {
+          if (data.restoreBest) {
+            network.restore_best();
+          } else {
+            network.rollback(data.epochs ?? 1);
+          }
+          postMessage({ restoredCheckpoint: true });
+          postAccuracy();
+        } catch (error) {
+          postMessage({ restoredCheckpoint: false, error: String(error) });
+        }
+      }
       if (data.requestCurrentImage) {
         console.log("Worker: Fetching current image...");
         const image: number = Math.min(
@@ -144,6 +189,7 @@ window.getNetworkWeights = () => {
       console.log("postAccuracy");
       const trainingAccuracy = network.accuracy(trainingDataset);
       const testingAccuracy = network.accuracy(testingDataset);
+      network.record_accuracy(trainingAccuracy, testingAccuracy);
       postMessage({
         accuracy: true,
         trainingAccuracy: trainingAccuracy,